@@ -1,3 +1,5 @@
+#![allow(clippy::needless_return)]
+
 use std::{
     fs::File,
     io::{Read, Write},
@@ -6,7 +8,7 @@ use std::{
 };
 
 use log::info;
-use ssdv::Quality;
+use ssdv::{Quality, TextFormat};
 
 const CALLSIGN: &[u8; 6] = b"SOMETH";
 const IMAGE_ID: u8 = 0;
@@ -38,9 +40,12 @@ fn main() -> ExitCode {
     for (i, chunk) in encoder.enumerate() {
         match chunk {
             Ok(c) => {
-                out_file
-                    .write_all(&c)
-                    .expect("Unable to write to output file");
+                // Text output has one hex packet per line
+                let result = match output.extension().and_then(|e| e.to_str()) {
+                    Some("txt") => writeln!(out_file, "{}", TextFormat::Hex.encode(&c)),
+                    _ => out_file.write_all(&c),
+                };
+                result.expect("Unable to write to output file");
 
                 // println!("{:?}", c);
             }
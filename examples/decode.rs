@@ -0,0 +1,85 @@
+#![allow(clippy::needless_return)]
+
+use std::{
+    fs::File,
+    io::{BufWriter, Read},
+    path::Path,
+    process::ExitCode,
+};
+
+use log::{info, warn};
+use ssdv::{parse_text_line, Decoder};
+
+const DEFAULT_PACKET_LENGTH: usize = 256;
+
+fn main() -> ExitCode {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() != 3 && args.len() != 4 {
+        println!("Usage: decode <INPUT> <OUTPUT.png|OUTPUT.ppm> [PACKET_LENGTH]");
+        return ExitCode::FAILURE;
+    }
+
+    let packet_length = match args.get(3).map(|l| l.parse::<usize>()) {
+        Some(Ok(length)) => length,
+        Some(Err(_)) => {
+            println!("Invalid packet length");
+            return ExitCode::FAILURE;
+        }
+        None => DEFAULT_PACKET_LENGTH,
+    };
+
+    let Ok(mut decoder) = Decoder::with_packet_length(packet_length) else {
+        println!("Packet length must be between 64 and 256 bytes");
+        return ExitCode::FAILURE;
+    };
+
+    let mut packets = Vec::new();
+    let input = Path::new(&args[1]);
+    let mut in_file = File::open(input).expect("Unable to open input file");
+    in_file
+        .read_to_end(&mut packets)
+        .expect("Unable to read from file");
+
+    // Text input has one hex or base64 packet per line
+    let packets: Vec<Vec<u8>> = match input.extension().and_then(|e| e.to_str()) {
+        Some("txt" | "log") => String::from_utf8_lossy(&packets)
+            .lines()
+            .filter_map(|line| parse_text_line(line, packet_length))
+            .map(|packet| packet.to_vec())
+            .collect(),
+        _ => packets.chunks(packet_length).map(|p| p.to_vec()).collect(),
+    };
+
+    for (i, packet) in packets.iter().enumerate() {
+        if let Err(err) = decoder.feed(&packet[..]) {
+            warn!("Dropped packet {i}: {err:?}");
+        }
+    }
+
+    let Some(image) = decoder.image() else {
+        println!("No valid packets found");
+        return ExitCode::FAILURE;
+    };
+
+    if !decoder.is_complete() {
+        warn!("Image is incomplete");
+    }
+
+    let output = Path::new(&args[2]);
+    let mut out_file = BufWriter::new(File::create(output).expect("Unable to create output file"));
+
+    let result = match output.extension().and_then(|e| e.to_str()) {
+        Some("ppm") => image.write_ppm(&mut out_file),
+        _ => image.write_png(&mut out_file),
+    };
+    result.expect("Unable to write to output file");
+
+    info!("Wrote {}x{} image", image.width(), image.height());
+
+    return ExitCode::SUCCESS;
+}
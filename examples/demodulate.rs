@@ -0,0 +1,107 @@
+#![allow(clippy::needless_return)]
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+    process::ExitCode,
+};
+
+use log::{info, warn};
+use ssdv::{read_wav, Decoder, Demodulator, Framer, ModemConfig, Modulation};
+
+const DEFAULT_PACKET_LENGTH: usize = 256;
+
+fn main() -> ExitCode {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args: Vec<String> = std::env::args().collect();
+
+    if !(3..=7).contains(&args.len()) {
+        println!("Usage: demodulate <INPUT.wav> <OUTPUT.png|OUTPUT.ppm> [rtty8n1|rtty8n2] [BAUD] [SHIFT] [PACKET_LENGTH]");
+        return ExitCode::FAILURE;
+    }
+
+    // The receiver only checks the first stop bit, so 8N1 and 8N2 are received the same way
+    let modulation = match args.get(3).map(|m| m.as_str()) {
+        Some("rtty8n1" | "rtty8n2") | None => Modulation::Rtty { stop_bits: 1 },
+        Some(_) => {
+            println!("Unknown modulation");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let packet_length = match args.get(6).map(|l| l.parse::<usize>()) {
+        Some(Ok(length)) => length,
+        Some(Err(_)) => {
+            println!("Invalid packet length");
+            return ExitCode::FAILURE;
+        }
+        None => DEFAULT_PACKET_LENGTH,
+    };
+
+    let (Ok(mut framer), Ok(mut decoder)) = (
+        Framer::with_packet_length(packet_length),
+        Decoder::with_packet_length(packet_length),
+    ) else {
+        println!("Packet length must be between 64 and 256 bytes");
+        return ExitCode::FAILURE;
+    };
+
+    let in_file = File::open(&args[1]).expect("Unable to open input file");
+    let (sample_rate, samples) =
+        read_wav(&mut BufReader::new(in_file)).expect("Unable to read WAV file");
+
+    let mut config = ModemConfig::new(modulation).with_sample_rate(sample_rate);
+    for (i, setting) in args.iter().enumerate().skip(4).take(2) {
+        let Ok(value) = setting.parse::<f64>() else {
+            println!("Invalid baud or shift");
+            return ExitCode::FAILURE;
+        };
+
+        config = match i {
+            4 => config.with_baud(value),
+            _ => config.with_shift(value),
+        };
+    }
+
+    let mut demodulator = match Demodulator::new(config) {
+        Ok(demodulator) => demodulator,
+        Err(err) => {
+            println!("Invalid modem settings: {err:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let data = demodulator.push(&samples);
+    let packets = framer.push_slice(&data);
+    info!("Received {} bytes, {} packets", data.len(), packets.len());
+
+    for (i, packet) in packets.iter().enumerate() {
+        if let Err(err) = decoder.feed(&packet[..]) {
+            warn!("Dropped packet {i}: {err:?}");
+        }
+    }
+
+    let Some(image) = decoder.image() else {
+        println!("No valid packets found");
+        return ExitCode::FAILURE;
+    };
+
+    if !decoder.is_complete() {
+        warn!("Image is incomplete");
+    }
+
+    let output = Path::new(&args[2]);
+    let mut out_file = BufWriter::new(File::create(output).expect("Unable to create output file"));
+
+    let result = match output.extension().and_then(|e| e.to_str()) {
+        Some("ppm") => image.write_ppm(&mut out_file),
+        _ => image.write_png(&mut out_file),
+    };
+    result.expect("Unable to write to output file");
+
+    return ExitCode::SUCCESS;
+}
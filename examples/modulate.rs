@@ -0,0 +1,84 @@
+#![allow(clippy::needless_return)]
+
+use std::{fs, fs::File, io::BufWriter, process::ExitCode, time::Duration};
+
+use log::{info, warn};
+use ssdv::{write_wav, Encoder, ModemConfig, Modulation, Modulator, Quality};
+
+const CALLSIGN: &[u8; 6] = b"SOMETH";
+const IMAGE_ID: u8 = 0;
+const QUALITY: Quality = Quality::Q3;
+
+/// Carrier before the first packet and after the last, for the receiver to lock on to
+const LEAD_TIME: Duration = Duration::from_secs(1);
+
+fn main() -> ExitCode {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args: Vec<String> = std::env::args().collect();
+
+    if !(3..=6).contains(&args.len()) {
+        println!("Usage: modulate <INPUT.jpg> <OUTPUT.wav> [rtty8n1|rtty8n2] [BAUD] [SHIFT]");
+        return ExitCode::FAILURE;
+    }
+
+    let modulation = match args.get(3).map(|m| m.as_str()) {
+        Some("rtty8n1") => Modulation::Rtty { stop_bits: 1 },
+        Some("rtty8n2") | None => Modulation::Rtty { stop_bits: 2 },
+        Some(_) => {
+            println!("Unknown modulation");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut config = ModemConfig::new(modulation);
+    for (i, setting) in args.iter().enumerate().skip(4) {
+        let Ok(value) = setting.parse::<f64>() else {
+            println!("Invalid baud or shift");
+            return ExitCode::FAILURE;
+        };
+
+        config = match i {
+            4 => config.with_baud(value),
+            _ => config.with_shift(value),
+        };
+    }
+
+    let mut modulator = match Modulator::new(config) {
+        Ok(modulator) => modulator,
+        Err(err) => {
+            println!("Invalid modem settings: {err:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let image = fs::read(&args[1]).expect("Unable to read input file");
+    let encoder = Encoder::new(*CALLSIGN, IMAGE_ID, QUALITY, image);
+
+    let mut samples = modulator.idle(LEAD_TIME);
+    let mut total = 0;
+    for (i, packet) in encoder.enumerate() {
+        match packet {
+            Ok(packet) => {
+                samples.extend(modulator.modulate(&packet));
+                total += 1;
+            }
+            Err(err) => warn!("Failed to encode packet {i}: {err:?}"),
+        }
+    }
+    samples.extend(modulator.idle(LEAD_TIME));
+
+    let mut out_file =
+        BufWriter::new(File::create(&args[2]).expect("Unable to create output file"));
+    write_wav(&mut out_file, config.sample_rate(), &samples)
+        .expect("Unable to write to output file");
+
+    info!(
+        "Wrote {total} packets, {:.1} seconds of audio",
+        samples.len() as f64 / config.sample_rate() as f64
+    );
+
+    return ExitCode::SUCCESS;
+}
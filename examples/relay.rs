@@ -0,0 +1,67 @@
+#![allow(clippy::needless_return)]
+
+use std::{
+    fs,
+    process::ExitCode,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use log::{info, warn};
+use ssdv::{Framer, ReceiverMetadata, RelaySender};
+
+const DEFAULT_PACKET_LENGTH: usize = 256;
+
+/// Pause between packets, so a whole capture doesn't overflow the server's receive buffer
+const PACKET_INTERVAL: Duration = Duration::from_millis(1);
+
+fn main() -> ExitCode {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args: Vec<String> = std::env::args().collect();
+
+    if !(3..=5).contains(&args.len()) {
+        println!("Usage: relay <SERVER_ADDR> <INPUT> [STATION] [PACKET_LENGTH]");
+        return ExitCode::FAILURE;
+    }
+
+    let packet_length = match args.get(4).map(|l| l.parse::<usize>()) {
+        Some(Ok(length)) => length,
+        Some(Err(_)) => {
+            println!("Invalid packet length");
+            return ExitCode::FAILURE;
+        }
+        None => DEFAULT_PACKET_LENGTH,
+    };
+
+    let Ok(mut framer) = Framer::with_packet_length(packet_length) else {
+        println!("Packet length must be between 64 and 256 bytes");
+        return ExitCode::FAILURE;
+    };
+
+    let sender = RelaySender::connect(&args[1], packet_length).expect("Unable to open socket");
+    let data = fs::read(&args[2]).expect("Unable to read input file");
+
+    // The input is whatever the receiver captured, noise and all
+    let mut sent = 0;
+    for packet in framer.push_slice(&data) {
+        let metadata = ReceiverMetadata {
+            timestamp: Some(SystemTime::now()),
+            station: args.get(3).cloned(),
+            ..Default::default()
+        };
+
+        match sender.send(&packet, &metadata) {
+            Ok(()) => sent += 1,
+            Err(err) => warn!("Unable to send packet: {err}"),
+        }
+
+        thread::sleep(PACKET_INTERVAL);
+    }
+
+    info!("Sent {sent} packets to {}", args[1]);
+
+    return ExitCode::SUCCESS;
+}
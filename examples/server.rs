@@ -0,0 +1,116 @@
+#![allow(clippy::needless_return)]
+
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{self, BufWriter},
+    path::Path,
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use ssdv::{ImageKey, MultiDecoder, ReceivedPacket, RelayServer};
+
+const DEFAULT_PACKET_LENGTH: usize = 256;
+
+/// Images are written out once packets stop arriving for this long, or at least this often
+const IDLE_TIME: Duration = Duration::from_secs(1);
+const WRITE_INTERVAL: Duration = Duration::from_secs(10);
+
+fn main() -> ExitCode {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() != 3 && args.len() != 4 {
+        println!("Usage: server <LISTEN_ADDR> <OUTPUT_DIR> [PACKET_LENGTH]");
+        return ExitCode::FAILURE;
+    }
+
+    let packet_length = match args.get(3).map(|l| l.parse::<usize>()) {
+        Some(Ok(length)) => length,
+        Some(Err(_)) => {
+            println!("Invalid packet length");
+            return ExitCode::FAILURE;
+        }
+        None => DEFAULT_PACKET_LENGTH,
+    };
+
+    let Ok(mut decoder) = MultiDecoder::with_packet_length(packet_length) else {
+        println!("Packet length must be between 64 and 256 bytes");
+        return ExitCode::FAILURE;
+    };
+
+    let server = RelayServer::bind(&args[1]).expect("Unable to open socket");
+    server
+        .set_read_timeout(Some(IDLE_TIME))
+        .expect("Unable to set socket timeout");
+
+    let output_dir = Path::new(&args[2]);
+
+    info!(
+        "Listening on {}",
+        server.local_addr().expect("Unable to open socket")
+    );
+
+    // Images with packets that haven't been written out yet
+    let mut updated = BTreeSet::new();
+    let mut last_write = Instant::now();
+
+    loop {
+        match server.recv() {
+            Ok((packet, metadata, from)) => {
+                match decoder.feed(ReceivedPacket::new(&packet, metadata)) {
+                    Ok(key) => {
+                        updated.insert(key);
+                    }
+                    Err(err) => warn!("Dropped packet from {from}: {err:?}"),
+                }
+
+                if last_write.elapsed() < WRITE_INTERVAL {
+                    continue;
+                }
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(err) => {
+                warn!("{err}");
+                continue;
+            }
+        }
+
+        for key in std::mem::take(&mut updated) {
+            write_image(&decoder, &key, output_dir);
+        }
+
+        last_write = Instant::now();
+    }
+}
+
+fn write_image(decoder: &MultiDecoder, key: &ImageKey, output_dir: &Path) {
+    let Some(decoder) = decoder.get(key) else {
+        return;
+    };
+
+    let Some(image) = decoder.image() else {
+        return;
+    };
+
+    let path = output_dir.join(format!(
+        "{}_{}_{}x{}.png",
+        key.callsign, key.image_id, key.width, key.height
+    ));
+    let result = File::create(&path).and_then(|f| image.write_png(&mut BufWriter::new(f)));
+
+    match result {
+        Ok(()) if decoder.is_complete() => info!("Wrote {}", path.display()),
+        Ok(()) => info!("Wrote {}, still incomplete", path.display()),
+        Err(err) => warn!("Unable to write {}: {err}", path.display()),
+    }
+}
@@ -0,0 +1,61 @@
+#![allow(clippy::needless_return)]
+
+use std::{fs, process::ExitCode};
+
+use ssdv::{simulate, Channel, Encoder, PacketType, Quality};
+
+const CALLSIGN: &[u8; 6] = b"SOMETH";
+const IMAGE_ID: u8 = 0;
+const QUALITY: Quality = Quality::Q3;
+
+const BIT_ERROR_RATES: [f64; 7] = [0.0, 1e-5, 1e-4, 1e-3, 3e-3, 5e-3, 1e-2];
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+
+    if !(2..=3).contains(&args.len()) {
+        println!("Usage: simulate <INPUT.jpg> [SEED]");
+        return ExitCode::FAILURE;
+    }
+
+    let seed = match args.get(2).map(|s| s.parse::<u64>()) {
+        Some(Ok(seed)) => seed,
+        Some(Err(_)) => {
+            println!("Invalid seed");
+            return ExitCode::FAILURE;
+        }
+        None => 0,
+    };
+
+    let image = fs::read(&args[1]).expect("Unable to read input file");
+
+    println!("{:>8}  {:>16}  {:>16}", "BER", "Normal", "No-FEC");
+
+    for ber in BIT_ERROR_RATES {
+        print!("{ber:>8}");
+
+        for packet_type in [PacketType::Normal, PacketType::NoFEC] {
+            let encoder = Encoder::new(*CALLSIGN, IMAGE_ID, QUALITY, image.clone())
+                .with_packet_type(packet_type);
+            let mut channel = Channel::new(seed).with_bit_error_rate(ber);
+
+            match simulate(encoder, &mut channel) {
+                Ok(report) => print!(
+                    "  {:>4}/{:<4} {:>5.1}%",
+                    report.decoded,
+                    report.sent,
+                    report.completeness * 100.0
+                ),
+                Err(err) => {
+                    println!();
+                    println!("Failed to encode image: {err:?}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+
+        println!();
+    }
+
+    return ExitCode::SUCCESS;
+}
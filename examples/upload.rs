@@ -0,0 +1,73 @@
+#![allow(clippy::needless_return)]
+
+use std::{fs, process::ExitCode, time::SystemTime};
+
+use log::{info, warn};
+use ssdv::{Framer, HttpUploader, UploadRecord, Uploader};
+
+const DEFAULT_PACKET_LENGTH: usize = 256;
+
+/// A server running locally, so nothing is uploaded anywhere public unless asked for
+const DEFAULT_URL: &str = "http://localhost:8080/api/v0/packets";
+
+/// Packets sent in each request
+const BATCH_SIZE: usize = 16;
+
+fn main() -> ExitCode {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args: Vec<String> = std::env::args().collect();
+
+    if !(3..=5).contains(&args.len()) {
+        println!("Usage: upload <INPUT> <RECEIVER> [URL] [PACKET_LENGTH]");
+        return ExitCode::FAILURE;
+    }
+
+    let url = args.get(3).map_or(DEFAULT_URL, |url| url.as_str());
+    let mut uploader = match HttpUploader::new(url) {
+        Ok(uploader) => uploader,
+        Err(err) => {
+            println!("Invalid URL: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let packet_length = match args.get(4).map(|l| l.parse::<usize>()) {
+        Some(Ok(length)) => length,
+        Some(Err(_)) => {
+            println!("Invalid packet length");
+            return ExitCode::FAILURE;
+        }
+        None => DEFAULT_PACKET_LENGTH,
+    };
+
+    let Ok(mut framer) = Framer::with_packet_length(packet_length) else {
+        println!("Packet length must be between 64 and 256 bytes");
+        return ExitCode::FAILURE;
+    };
+
+    let data = fs::read(&args[1]).expect("Unable to read input file");
+
+    // The framer has already checked each packet, so the records can't fail
+    let records: Vec<UploadRecord> = framer
+        .push_slice(&data)
+        .iter()
+        .filter_map(|packet| {
+            UploadRecord::new(packet, packet_length, &args[2], SystemTime::now()).ok()
+        })
+        .collect();
+
+    let mut uploaded = 0;
+    for batch in records.chunks(BATCH_SIZE) {
+        match uploader.upload(batch) {
+            Ok(()) => uploaded += batch.len(),
+            Err(err) => warn!("Unable to upload {} packets: {err}", batch.len()),
+        }
+    }
+
+    info!("Uploaded {uploaded} of {} packets to {url}", records.len());
+
+    return ExitCode::SUCCESS;
+}
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ssdv::{Encoder, Quality};
+
+fuzz_target!(|data: &[u8]| {
+    let encoder = Encoder::new(*b"FUZZER", 0, Quality::Q4, data.to_vec());
+    for _ in encoder {}
+});
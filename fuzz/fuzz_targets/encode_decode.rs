@@ -0,0 +1,36 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ssdv::{Decoder, Encoder, PacketType, Quality};
+
+// The first few bytes pick the encoder settings, the rest is the image
+fuzz_target!(|data: &[u8]| {
+    let [quality, packet_type, packet_length, flags, image @ ..] = data else {
+        return;
+    };
+
+    let quality = Quality::from_num(quality & 7).unwrap();
+    let chroma_quality = Quality::from_num(quality.num().saturating_sub((flags >> 2) & 3)).unwrap();
+    let packet_type = if packet_type & 1 == 0 {
+        PacketType::Normal
+    } else {
+        PacketType::NoFEC
+    };
+    let packet_length = 64 + *packet_length as usize % 193;
+
+    let encoder = Encoder::new(*b"FUZZER", 0, quality, image.to_vec())
+        .with_packet_type(packet_type)
+        .with_packet_length(packet_length)
+        .unwrap()
+        .with_flush_truncated(flags & 1 != 0)
+        .with_grayscale(flags & 2 != 0)
+        .with_chroma_quality(chroma_quality)
+        .unwrap();
+
+    let mut decoder = Decoder::with_packet_length(packet_length).unwrap();
+    for packet in encoder.flatten() {
+        let _ = decoder.feed(&packet[..]);
+    }
+
+    let _ = decoder.image();
+});
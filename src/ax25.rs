@@ -0,0 +1,242 @@
+// AX.25 UI (unnumbered information) frames, for sending packets over
+// APRS-style links. Frames are built without the FCS, which the TNC or HDLC
+// layer adds and checks itself, so they can be passed straight to KISS.
+
+use arrayvec::ArrayVec;
+
+/// Control field of a UI frame, with the poll/final bit clear
+const UI: u8 = 0x03;
+const POLL_FINAL: u8 = 0x10;
+
+/// Protocol id for frames with no layer 3 protocol
+const NO_LAYER_3: u8 = 0xF0;
+
+/// The most addresses a frame can have, the destination, source and up to 8 digipeaters
+const MAX_ADDRESSES: usize = 10;
+
+/// A station's callsign and SSID
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Ax25Address {
+    callsign: ArrayVec<u8, 6>,
+    ssid: u8,
+}
+
+impl Ax25Address {
+    /// Creates an address from a callsign of up to 6 letters and digits, and an SSID of 0-15
+    pub fn new(callsign: &str, ssid: u8) -> Result<Self, Ax25Error> {
+        if callsign.is_empty() || !callsign.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(Ax25Error::Callsign);
+        }
+
+        let callsign = ArrayVec::try_from(callsign.to_ascii_uppercase().as_bytes())
+            .map_err(|_| Ax25Error::Callsign)?;
+
+        if ssid > 15 {
+            return Err(Ax25Error::Ssid);
+        }
+
+        return Ok(Self { callsign, ssid });
+    }
+
+    pub fn callsign(&self) -> &str {
+        // Only ever holds ASCII letters and digits
+        return std::str::from_utf8(&self.callsign).unwrap_or_default();
+    }
+
+    pub fn ssid(&self) -> u8 {
+        return self.ssid;
+    }
+
+    /// Encodes the address field, with the callsign shifted up a bit and padded with spaces
+    fn encode(&self, command: bool, last: bool) -> [u8; 7] {
+        let mut field = [b' ' << 1; 7];
+        for (f, c) in field.iter_mut().zip(&self.callsign) {
+            *f = c << 1;
+        }
+
+        // The two reserved bits are set
+        field[6] = ((command as u8) << 7) | 0x60 | (self.ssid << 1) | last as u8;
+        return field;
+    }
+
+    fn decode(field: &[u8]) -> Result<Self, Ax25Error> {
+        let callsign: ArrayVec<u8, 6> = field[..6]
+            .iter()
+            .map(|b| b >> 1)
+            .take_while(|b| *b != b' ')
+            .collect();
+
+        if callsign.is_empty() || !callsign.iter().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(Ax25Error::Callsign);
+        }
+
+        return Ok(Self {
+            callsign,
+            ssid: (field[6] >> 1) & 0x0F,
+        });
+    }
+}
+
+/// An AX.25 UI frame, carrying one SSDV packet in its info field
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UiFrame {
+    pub destination: Ax25Address,
+    pub source: Ax25Address,
+    pub info: Vec<u8>,
+}
+
+impl UiFrame {
+    pub fn new(destination: Ax25Address, source: Ax25Address, info: &[u8]) -> Self {
+        Self {
+            destination,
+            source,
+            info: info.to_vec(),
+        }
+    }
+
+    /// Encodes the frame as a command, without the FCS
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(16 + self.info.len());
+        frame.extend_from_slice(&self.destination.encode(true, false));
+        frame.extend_from_slice(&self.source.encode(false, true));
+        frame.push(UI);
+        frame.push(NO_LAYER_3);
+        frame.extend_from_slice(&self.info);
+
+        return frame;
+    }
+
+    /// Parses a frame without its FCS, such as one received from a TNC over KISS.
+    /// Any digipeater addresses are skipped.
+    pub fn parse(frame: &[u8]) -> Result<Self, Ax25Error> {
+        // The address fields end with the low bit of their last byte set
+        let Some(addresses) = frame
+            .chunks(7)
+            .take(MAX_ADDRESSES)
+            .position(|field| field.len() == 7 && field[6] & 1 == 1)
+            .map(|last| last + 1)
+        else {
+            return Err(Ax25Error::Length);
+        };
+
+        if addresses < 2 {
+            return Err(Ax25Error::Length);
+        }
+
+        let (control, pid) = match frame.get(addresses * 7..addresses * 7 + 2) {
+            Some(&[control, pid]) => (control, pid),
+            _ => return Err(Ax25Error::Length),
+        };
+
+        if control & !POLL_FINAL != UI {
+            return Err(Ax25Error::Control);
+        }
+
+        if pid != NO_LAYER_3 {
+            return Err(Ax25Error::Pid);
+        }
+
+        return Ok(Self {
+            destination: Ax25Address::decode(&frame[0..7])?,
+            source: Ax25Address::decode(&frame[7..14])?,
+            info: frame[addresses * 7 + 2..].to_vec(),
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ax25Error {
+    /// Callsigns must be 1-6 letters and digits
+    Callsign,
+    /// SSIDs must be between 0 and 15
+    Ssid,
+    /// The frame is too short, or its address fields never end
+    Length,
+    /// The frame is not a UI frame
+    Control,
+    /// The frame carries a layer 3 protocol
+    Pid,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> UiFrame {
+        UiFrame::new(
+            Ax25Address::new("ssdv", 0).unwrap(),
+            Ax25Address::new("M0XYZ", 11).unwrap(),
+            b"packet",
+        )
+    }
+
+    #[test]
+    fn validates_addresses() {
+        let address = Ax25Address::new("m0xyz", 5).unwrap();
+        assert_eq!(address.callsign(), "M0XYZ");
+        assert_eq!(address.ssid(), 5);
+
+        assert_eq!(Ax25Address::new("", 0), Err(Ax25Error::Callsign));
+        assert_eq!(Ax25Address::new("TOOLONG", 0), Err(Ax25Error::Callsign));
+        assert_eq!(Ax25Address::new("M0-XY", 0), Err(Ax25Error::Callsign));
+        assert_eq!(Ax25Address::new("M0XYZ", 16), Err(Ax25Error::Ssid));
+    }
+
+    #[test]
+    fn encodes_ui_frames() {
+        let encoded = frame().encode();
+
+        assert_eq!(
+            encoded[..7],
+            [b'S' << 1, b'S' << 1, b'D' << 1, b'V' << 1, 0x40, 0x40, 0xE0]
+        );
+        assert_eq!(
+            encoded[7..14],
+            [
+                b'M' << 1,
+                b'0' << 1,
+                b'X' << 1,
+                b'Y' << 1,
+                b'Z' << 1,
+                0x40,
+                0x77
+            ]
+        );
+        assert_eq!(encoded[14..16], [UI, NO_LAYER_3]);
+        assert_eq!(&encoded[16..], b"packet");
+
+        assert_eq!(UiFrame::parse(&encoded), Ok(frame()));
+    }
+
+    #[test]
+    fn skips_digipeaters() {
+        let mut encoded = frame().encode();
+
+        // Clear the end of address bit on the source and add a digipeater after it
+        encoded[13] &= !1;
+        let digipeater = Ax25Address::new("WIDE1", 1).unwrap().encode(false, true);
+        encoded.splice(14..14, digipeater);
+
+        assert_eq!(UiFrame::parse(&encoded), Ok(frame()));
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        let encoded = frame().encode();
+
+        assert_eq!(UiFrame::parse(&encoded[..10]), Err(Ax25Error::Length));
+        assert_eq!(UiFrame::parse(&encoded[..15]), Err(Ax25Error::Length));
+
+        let mut unterminated = encoded.clone();
+        unterminated[13] &= !1;
+        assert_eq!(UiFrame::parse(&unterminated), Err(Ax25Error::Length));
+
+        let mut control = encoded.clone();
+        control[14] = 0x00;
+        assert_eq!(UiFrame::parse(&control), Err(Ax25Error::Control));
+
+        let mut pid = encoded;
+        pid[15] = 0xCC;
+        assert_eq!(UiFrame::parse(&pid), Err(Ax25Error::Pid));
+    }
+}
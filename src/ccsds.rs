@@ -0,0 +1,493 @@
+// CCSDS Space Packets (CCSDS 133.0-B) and TM Transfer Frames (CCSDS 132.0-B),
+// so SSDV packets can ride an existing spacecraft telemetry downlink. Each
+// SSDV packet goes in its own space packet, and the space packets are laid end
+// to end across the data fields of fixed length transfer frames. Sync markers
+// and channel coding are left to the rest of the telemetry stack.
+
+/// APID reserved for idle packets, which fill out frames with nothing to send
+const IDLE_APID: u16 = 0x7FF;
+
+const PACKET_HEADER_SIZE: usize = 6;
+const FRAME_HEADER_SIZE: usize = 6;
+const FECF_SIZE: usize = 2;
+
+/// First header pointer for a frame with no packet starting in it
+const NO_PACKET_START: u16 = 0x7FF;
+
+/// A telemetry space packet with no secondary header, carrying one SSDV packet
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SpacePacket {
+    /// Application process id, 0-2046
+    pub apid: u16,
+    /// Sequence count, 0-16383
+    pub sequence: u16,
+    pub data: Vec<u8>,
+}
+
+impl SpacePacket {
+    /// Encodes the packet, as a standalone (unsegmented) packet
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(PACKET_HEADER_SIZE + self.data.len());
+        packet.extend_from_slice(&(self.apid & 0x7FF).to_be_bytes());
+        packet.extend_from_slice(&(0xC000 | (self.sequence & 0x3FFF)).to_be_bytes());
+        packet.extend_from_slice(&(self.data.len().saturating_sub(1) as u16).to_be_bytes());
+        packet.extend_from_slice(&self.data);
+
+        return packet;
+    }
+
+    /// Parses one whole packet
+    pub fn parse(packet: &[u8]) -> Result<Self, CcsdsError> {
+        if packet.len() <= PACKET_HEADER_SIZE {
+            return Err(CcsdsError::Length);
+        }
+
+        if packet[0] >> 5 != 0 {
+            return Err(CcsdsError::Version);
+        }
+
+        let length = u16::from_be_bytes([packet[4], packet[5]]) as usize + 1;
+        if packet.len() != PACKET_HEADER_SIZE + length {
+            return Err(CcsdsError::Length);
+        }
+
+        return Ok(Self {
+            apid: u16::from_be_bytes([packet[0], packet[1]]) & 0x7FF,
+            sequence: u16::from_be_bytes([packet[2], packet[3]]) & 0x3FFF,
+            data: packet[PACKET_HEADER_SIZE..].to_vec(),
+        });
+    }
+
+    /// Length of the packet starting at the beginning of `data`, once enough of its header is there
+    fn length(data: &[u8]) -> Option<usize> {
+        let length = data.get(4..PACKET_HEADER_SIZE)?;
+        return Some(PACKET_HEADER_SIZE + u16::from_be_bytes([length[0], length[1]]) as usize + 1);
+    }
+}
+
+/// Wraps SSDV packets in space packets for one APID, counting them as it goes
+#[derive(Debug, Clone)]
+pub struct Packetizer {
+    apid: u16,
+    sequence: u16,
+}
+
+impl Packetizer {
+    pub fn new(apid: u16) -> Result<Self, CcsdsError> {
+        if apid >= IDLE_APID {
+            return Err(CcsdsError::Apid);
+        }
+
+        return Ok(Self { apid, sequence: 0 });
+    }
+
+    pub fn packet(&mut self, data: &[u8]) -> SpacePacket {
+        let packet = SpacePacket {
+            apid: self.apid,
+            sequence: self.sequence,
+            data: data.to_vec(),
+        };
+
+        self.sequence = (self.sequence + 1) & 0x3FFF;
+        return packet;
+    }
+}
+
+/// Which virtual channel of which spacecraft the frames belong to, and how they're laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TmChannel {
+    spacecraft_id: u16,
+    vcid: u8,
+    frame_length: usize,
+    fecf: bool,
+}
+
+impl TmChannel {
+    /// Creates a channel with a 10-bit spacecraft id, a virtual channel id of 0-7, and
+    /// frames of `frame_length` bytes (up to 2048). `fecf` adds a CRC to the end of each
+    /// frame, for links without other error control.
+    pub fn new(
+        spacecraft_id: u16,
+        vcid: u8,
+        frame_length: usize,
+        fecf: bool,
+    ) -> Result<Self, CcsdsError> {
+        if spacecraft_id > 0x3FF {
+            return Err(CcsdsError::SpacecraftId);
+        }
+
+        if vcid > 7 {
+            return Err(CcsdsError::Vcid);
+        }
+
+        let channel = Self {
+            spacecraft_id,
+            vcid,
+            frame_length,
+            fecf,
+        };
+
+        // The data field must at least fit a packet header
+        if frame_length > 2048 || channel.data_field_length() <= PACKET_HEADER_SIZE {
+            return Err(CcsdsError::FrameLength);
+        }
+
+        return Ok(channel);
+    }
+
+    fn data_field_length(&self) -> usize {
+        let fecf = if self.fecf { FECF_SIZE } else { 0 };
+        return self.frame_length.saturating_sub(FRAME_HEADER_SIZE + fecf);
+    }
+}
+
+/// Lays space packets end to end across the data fields of TM transfer frames
+#[derive(Debug, Clone)]
+pub struct TmFramer {
+    channel: TmChannel,
+    master_count: u8,
+    virtual_count: u8,
+    data: Vec<u8>,
+    /// Where the first packet starting in the current frame begins
+    first_header: Option<usize>,
+}
+
+impl TmFramer {
+    pub fn new(channel: TmChannel) -> Self {
+        Self {
+            channel,
+            master_count: 0,
+            virtual_count: 0,
+            data: Vec::new(),
+            first_header: None,
+        }
+    }
+
+    /// Adds a space packet, returning the frames it completes
+    pub fn push(&mut self, packet: &SpacePacket) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        self.push_packet(&packet.encode(), &mut frames);
+
+        return frames;
+    }
+
+    /// Completes the current frame with an idle packet, if any packets are waiting in it
+    pub fn flush(&mut self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        if self.data.is_empty() {
+            return frames;
+        }
+
+        // An idle packet too short to fill the frame runs over into another
+        let mut length = self.channel.data_field_length() - self.data.len();
+        if length <= PACKET_HEADER_SIZE {
+            length += self.channel.data_field_length();
+        }
+
+        let idle = SpacePacket {
+            apid: IDLE_APID,
+            sequence: 0,
+            data: vec![0x55; length - PACKET_HEADER_SIZE],
+        };
+
+        self.push_packet(&idle.encode(), &mut frames);
+        return frames;
+    }
+
+    fn push_packet(&mut self, mut packet: &[u8], frames: &mut Vec<Vec<u8>>) {
+        if self.first_header.is_none() {
+            self.first_header = Some(self.data.len());
+        }
+
+        while !packet.is_empty() {
+            let space = self.channel.data_field_length() - self.data.len();
+            let (now, later) = packet.split_at(space.min(packet.len()));
+            self.data.extend_from_slice(now);
+            packet = later;
+
+            if self.data.len() == self.channel.data_field_length() {
+                frames.push(self.frame());
+            }
+        }
+    }
+
+    /// Assembles a frame from the full data field
+    fn frame(&mut self) -> Vec<u8> {
+        let channel = &self.channel;
+
+        let first_header = self
+            .first_header
+            .take()
+            .map_or(NO_PACKET_START, |first_header| first_header as u16);
+
+        let mut frame = Vec::with_capacity(channel.frame_length);
+        // Version 0, with no operational control field
+        frame.extend_from_slice(
+            &((channel.spacecraft_id << 4) | ((channel.vcid as u16) << 1)).to_be_bytes(),
+        );
+        frame.push(self.master_count);
+        frame.push(self.virtual_count);
+        // No secondary header, packets in order, with the segment length id for unsegmented data
+        frame.extend_from_slice(&(0x1800 | first_header).to_be_bytes());
+        frame.append(&mut self.data);
+
+        if channel.fecf {
+            frame.extend_from_slice(&crc16(&frame).to_be_bytes());
+        }
+
+        self.master_count = self.master_count.wrapping_add(1);
+        self.virtual_count = self.virtual_count.wrapping_add(1);
+
+        return frame;
+    }
+}
+
+/// Extracts space packets from the TM transfer frames of one virtual channel. Packets
+/// cut short by lost frames are dropped, picking up again at the next packet to start
+/// in a frame.
+#[derive(Debug, Clone)]
+pub struct TmDeframer {
+    channel: TmChannel,
+    virtual_count: Option<u8>,
+    data: Vec<u8>,
+    /// The data starts at the beginning of a packet
+    synced: bool,
+}
+
+impl TmDeframer {
+    pub fn new(channel: TmChannel) -> Self {
+        Self {
+            channel,
+            virtual_count: None,
+            data: Vec::new(),
+            synced: false,
+        }
+    }
+
+    /// Adds the next frame, returning the packets it completes. Frames for other spacecraft
+    /// or virtual channels are ignored, and idle packets are dropped.
+    pub fn push(&mut self, frame: &[u8]) -> Result<Vec<SpacePacket>, CcsdsError> {
+        let channel = self.channel;
+
+        if frame.len() != channel.frame_length {
+            return Err(CcsdsError::Length);
+        }
+
+        if channel.fecf {
+            let (frame, fecf) = frame.split_at(frame.len() - FECF_SIZE);
+            if crc16(frame).to_be_bytes() != fecf {
+                return Err(CcsdsError::Crc);
+            }
+        }
+
+        let id = u16::from_be_bytes([frame[0], frame[1]]);
+        if id >> 14 != 0 {
+            return Err(CcsdsError::Version);
+        }
+
+        if (id >> 4) & 0x3FF != channel.spacecraft_id || (id >> 1) as u8 & 7 != channel.vcid {
+            return Ok(Vec::new());
+        }
+
+        // A gap in the count means frames were lost, along with the rest of the current packet
+        let virtual_count = frame[3];
+        if self
+            .virtual_count
+            .is_some_and(|c| c.wrapping_add(1) != virtual_count)
+        {
+            self.data.clear();
+            self.synced = false;
+        }
+        self.virtual_count = Some(virtual_count);
+
+        let first_header = (u16::from_be_bytes([frame[4], frame[5]]) & 0x7FF) as usize;
+        let data = &frame[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + channel.data_field_length()];
+
+        // The first header pointer is past the end of the data when no packet starts in this
+        // frame, or it only holds idle data
+        if self.synced {
+            self.data.extend_from_slice(data);
+        } else if first_header < data.len() {
+            self.data.extend_from_slice(&data[first_header..]);
+            self.synced = true;
+        }
+
+        let mut packets = Vec::new();
+        while let Some(length) = SpacePacket::length(&self.data) {
+            if self.data.len() < length {
+                break;
+            }
+
+            let packet: Vec<u8> = self.data.drain(..length).collect();
+            match SpacePacket::parse(&packet) {
+                Ok(packet) if packet.apid == IDLE_APID => {}
+                Ok(packet) => packets.push(packet),
+                Err(_) => {
+                    // Lost track of the packet boundaries, wait for the next frame to mark one
+                    self.data.clear();
+                    self.synced = false;
+                }
+            }
+        }
+
+        return Ok(packets);
+    }
+}
+
+/// CRC-16-CCITT, as used for the frame error control field
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+
+    return crc;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CcsdsError {
+    /// APIDs must be between 0 and 2046, 2047 is reserved for idle packets
+    Apid,
+    /// Spacecraft ids must be between 0 and 1023
+    SpacecraftId,
+    /// Virtual channel ids must be between 0 and 7
+    Vcid,
+    /// Frames must be at most 2048 bytes, with room for at least a packet header
+    FrameLength,
+    /// The packet or frame is the wrong length
+    Length,
+    /// The packet or frame has an unsupported version number
+    Version,
+    /// The frame failed its CRC check
+    Crc,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packets() -> Vec<SpacePacket> {
+        let mut packetizer = Packetizer::new(0x123).unwrap();
+        (0..10u8)
+            .map(|i| packetizer.packet(&vec![i; 100 + i as usize * 10]))
+            .collect()
+    }
+
+    fn frames(channel: TmChannel, packets: &[SpacePacket]) -> Vec<Vec<u8>> {
+        let mut framer = TmFramer::new(channel);
+        let mut frames: Vec<Vec<u8>> = packets.iter().flat_map(|p| framer.push(p)).collect();
+        frames.extend(framer.flush());
+        frames
+    }
+
+    #[test]
+    fn encodes_space_packets() {
+        let packet = SpacePacket {
+            apid: 0x123,
+            sequence: 5,
+            data: vec![0xAA, 0xBB, 0xCC],
+        };
+
+        let encoded = packet.encode();
+        assert_eq!(
+            encoded,
+            [0x01, 0x23, 0xC0, 0x05, 0x00, 0x02, 0xAA, 0xBB, 0xCC]
+        );
+        assert_eq!(SpacePacket::parse(&encoded), Ok(packet));
+
+        assert_eq!(SpacePacket::parse(&encoded[..8]), Err(CcsdsError::Length));
+        assert_eq!(SpacePacket::parse(&encoded[..6]), Err(CcsdsError::Length));
+    }
+
+    #[test]
+    fn counts_packets() {
+        assert_eq!(Packetizer::new(IDLE_APID).err(), Some(CcsdsError::Apid));
+
+        let sequences: Vec<u16> = packets().iter().map(|p| p.sequence).collect();
+        assert_eq!(sequences, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn validates_channels() {
+        assert_eq!(
+            TmChannel::new(0x400, 0, 223, false),
+            Err(CcsdsError::SpacecraftId)
+        );
+        assert_eq!(TmChannel::new(0x3FF, 8, 223, false), Err(CcsdsError::Vcid));
+        assert_eq!(
+            TmChannel::new(0x3FF, 7, 2049, false),
+            Err(CcsdsError::FrameLength)
+        );
+        assert_eq!(
+            TmChannel::new(0x3FF, 7, 14, true),
+            Err(CcsdsError::FrameLength)
+        );
+        assert!(TmChannel::new(0x3FF, 7, 2048, true).is_ok());
+    }
+
+    #[test]
+    fn frames_and_deframes_packets() {
+        let channel = TmChannel::new(0x2A, 3, 223, true).unwrap();
+        let packets = packets();
+        let frames = frames(channel, &packets);
+
+        assert!(frames.iter().all(|f| f.len() == 223));
+        assert_eq!(frames[0][..4], [0x02, 0xA6, 0x00, 0x00]);
+        assert_eq!(frames[0][4..6], [0x18, 0x00]);
+
+        let mut deframer = TmDeframer::new(channel);
+        let received: Vec<SpacePacket> = frames
+            .iter()
+            .flat_map(|f| deframer.push(f).unwrap())
+            .collect();
+
+        assert_eq!(received, packets);
+    }
+
+    #[test]
+    fn resyncs_after_lost_frames() {
+        let channel = TmChannel::new(0x2A, 3, 223, false).unwrap();
+        let packets = packets();
+        let mut frames = frames(channel, &packets);
+        frames.remove(2);
+
+        let mut deframer = TmDeframer::new(channel);
+        let received: Vec<SpacePacket> = frames
+            .iter()
+            .flat_map(|f| deframer.push(f).unwrap())
+            .collect();
+
+        // The packets overlapping the lost frame are dropped, the rest arrive intact
+        assert!(received.len() < packets.len());
+        assert!(received.iter().all(|p| packets.contains(p)));
+        assert_eq!(received.last(), packets.last());
+    }
+
+    #[test]
+    fn rejects_damaged_frames() {
+        let channel = TmChannel::new(0x2A, 3, 223, true).unwrap();
+        let mut frames = frames(channel, &packets());
+        frames[0][10] ^= 1;
+
+        let mut deframer = TmDeframer::new(channel);
+        assert_eq!(deframer.push(&frames[0]), Err(CcsdsError::Crc));
+        assert_eq!(deframer.push(&frames[1][..100]), Err(CcsdsError::Length));
+
+        // Frames for other virtual channels are skipped
+        let other = TmChannel::new(0x2A, 4, 223, true).unwrap();
+        assert_eq!(TmDeframer::new(other).push(&frames[1]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+}
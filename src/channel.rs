@@ -0,0 +1,334 @@
+// Simulated radio links, for tuning packet types and lengths before flying them.
+// Packets pass through a channel that flips bits, corrupts bursts of bytes,
+// erases bytes and drops whole packets, and what arrives is decoded to see how
+// much of the image survived. Each kind of damage is drawn from its own seeded
+// generator, so a run with the same seed and settings always gives the same
+// result, and turning one kind of damage up or down leaves the others as they were.
+
+use crate::{
+    decoder,
+    encoder::{Encoder, PACKET_SIZE},
+    Decoder, EncodeError,
+};
+
+/// SplitMix64, small and good enough for simulating noise
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        return z ^ (z >> 31);
+    }
+
+    /// A uniformly distributed value in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        return (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        return self.next_f64() < probability;
+    }
+}
+
+/// A packet that made it across a [`Channel`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Arrival {
+    pub data: Vec<u8>,
+    /// Positions in `data` of the bytes that were erased, in order
+    pub erasures: Vec<usize>,
+}
+
+/// A simulated link that damages the packets sent through it
+#[derive(Debug, Clone)]
+pub struct Channel {
+    bit_rng: Rng,
+    burst_rng: Rng,
+    erasure_rng: Rng,
+    loss_rng: Rng,
+    bit_error_rate: f64,
+    burst_rate: f64,
+    burst_length: usize,
+    erasure_rate: f64,
+    packet_loss: f64,
+    /// Bytes left in the current burst, which can run on into the next packet
+    burst_remaining: usize,
+}
+
+impl Channel {
+    /// Creates a perfect channel, with damage drawn from a generator seeded with `seed`
+    pub fn new(seed: u64) -> Self {
+        let mut seeds = Rng(seed);
+
+        Self {
+            bit_rng: Rng(seeds.next_u64()),
+            burst_rng: Rng(seeds.next_u64()),
+            erasure_rng: Rng(seeds.next_u64()),
+            loss_rng: Rng(seeds.next_u64()),
+            bit_error_rate: 0.0,
+            burst_rate: 0.0,
+            burst_length: 0,
+            erasure_rate: 0.0,
+            packet_loss: 0.0,
+            burst_remaining: 0,
+        }
+    }
+
+    /// Flips each bit independently with probability `bit_error_rate`
+    pub fn with_bit_error_rate(mut self, bit_error_rate: f64) -> Self {
+        self.bit_error_rate = bit_error_rate;
+        return self;
+    }
+
+    /// Starts a burst at each byte with probability `rate`, replacing it and the
+    /// `length - 1` bytes after it with random bytes
+    pub fn with_bursts(mut self, rate: f64, length: usize) -> Self {
+        self.burst_rate = rate;
+        self.burst_length = length;
+        return self;
+    }
+
+    /// Erases each byte with probability `erasure_rate`, leaving a zero in its place as a
+    /// receiver does when it knows a byte was missed but not what it was. The positions
+    /// of the erased bytes arrive with the packet, for the FEC to fill them in.
+    pub fn with_erasure_rate(mut self, erasure_rate: f64) -> Self {
+        self.erasure_rate = erasure_rate;
+        return self;
+    }
+
+    /// Loses whole packets with probability `packet_loss`
+    pub fn with_packet_loss(mut self, packet_loss: f64) -> Self {
+        self.packet_loss = packet_loss;
+        return self;
+    }
+
+    /// Sends a packet through the channel, returning what arrives, or `None` if it was lost
+    pub fn transmit(&mut self, packet: &[u8]) -> Option<Arrival> {
+        let mut data = packet.to_vec();
+        let mut erasures = Vec::new();
+
+        for (i, byte) in data.iter_mut().enumerate() {
+            if self.burst_remaining == 0 && self.burst_rng.chance(self.burst_rate) {
+                self.burst_remaining = self.burst_length;
+            }
+
+            if self.burst_remaining > 0 {
+                self.burst_remaining -= 1;
+                *byte = self.burst_rng.next_u64() as u8;
+            }
+
+            for bit in 0..8 {
+                if self.bit_rng.chance(self.bit_error_rate) {
+                    *byte ^= 1 << bit;
+                }
+            }
+
+            if self.erasure_rng.chance(self.erasure_rate) {
+                *byte = 0;
+                erasures.push(i);
+            }
+        }
+
+        if self.loss_rng.chance(self.packet_loss) {
+            return None;
+        }
+
+        return Some(Arrival { data, erasures });
+    }
+}
+
+/// How much of an image made it across a [`Channel`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkReport {
+    /// Packets sent
+    pub sent: usize,
+    /// Packets that arrived, damaged or not
+    pub arrived: usize,
+    /// Packets the decoder accepted, after any repairs with their FEC bytes
+    pub decoded: usize,
+    /// Fraction of the image's MCUs decoded, from 0 to 1
+    pub completeness: f64,
+}
+
+/// Encodes an image, sends each packet through `channel` and decodes whatever arrives
+pub fn simulate(encoder: Encoder, channel: &mut Channel) -> Result<LinkReport, EncodeError> {
+    // The encoder has already checked its packet length
+    let packet_length = encoder.packet_length;
+    let mut decoder =
+        Decoder::with_packet_length(packet_length).map_err(|_| EncodeError::PacketLength)?;
+
+    let mut report = LinkReport {
+        sent: 0,
+        arrived: 0,
+        decoded: 0,
+        completeness: 0.0,
+    };
+
+    for packet in encoder {
+        let packet = packet?;
+        report.sent += 1;
+
+        let Some(arrival) = channel.transmit(&packet) else {
+            continue;
+        };
+        report.arrived += 1;
+
+        // The decoder can only repair errors, so fill in the erasures while their
+        // positions are known
+        let mut data = [0; PACKET_SIZE];
+        data[..packet_length].copy_from_slice(&arrival.data[..packet_length]);
+        if !arrival.erasures.is_empty() {
+            decoder::repair_erasures(&mut data, packet_length, &arrival.erasures);
+        }
+
+        if decoder.feed(&data[..packet_length]).is_ok() {
+            report.decoded += 1;
+        }
+    }
+
+    report.completeness = decoder.completeness();
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PacketType, Quality};
+
+    fn encoder() -> Encoder {
+        return Encoder::new(
+            *b"SOMETH",
+            0,
+            Quality::Q3,
+            include_bytes!("../balloon.jpg").to_vec(),
+        )
+        .with_packet_type(PacketType::Normal);
+    }
+
+    /// Sends 1000 packets of zeros, returning the fraction of bits and of bytes damaged
+    fn damage(channel: &mut Channel) -> (f64, f64) {
+        let (mut bits, mut bytes) = (0, 0);
+
+        for _ in 0..1000 {
+            let arrival = channel.transmit(&[0; PACKET_SIZE]).unwrap();
+            bits += arrival.data.iter().map(|b| b.count_ones()).sum::<u32>();
+            bytes += arrival.data.iter().filter(|b| **b != 0).count();
+        }
+
+        let total = 1000.0 * PACKET_SIZE as f64;
+        return (bits as f64 / (total * 8.0), bytes as f64 / total);
+    }
+
+    #[test]
+    fn passes_everything_over_a_perfect_channel() {
+        let packet: Vec<u8> = (0..=255).collect();
+        let arrival = Channel::new(1).transmit(&packet).unwrap();
+        assert_eq!(arrival.data, packet);
+        assert!(arrival.erasures.is_empty());
+
+        let report = simulate(encoder(), &mut Channel::new(1)).unwrap();
+        assert!(report.sent > 0);
+        assert_eq!(report.arrived, report.sent);
+        assert_eq!(report.decoded, report.sent);
+        assert_eq!(report.completeness, 1.0);
+    }
+
+    #[test]
+    fn flips_bits_at_the_given_rate() {
+        let (bits, _) = damage(&mut Channel::new(1).with_bit_error_rate(0.006));
+        assert!((0.0057..0.0063).contains(&bits), "{bits}");
+
+        // About 12 bytes in error per packet, so the FEC repairs most but not all of them
+        let mut channel = Channel::new(1).with_bit_error_rate(0.006);
+        let report = simulate(encoder(), &mut channel).unwrap();
+        assert_eq!(report.arrived, report.sent);
+        assert!(report.decoded > report.sent / 2, "{report:?}");
+        assert!(report.decoded < report.sent, "{report:?}");
+        assert!((0.0..1.0).contains(&report.completeness), "{report:?}");
+    }
+
+    #[test]
+    fn corrupts_bursts_of_bytes() {
+        // Bursts of 8 bytes start at 1% of the bytes outside a burst, about 7% in all,
+        // less the random bytes that happen to be zero
+        let (_, bytes) = damage(&mut Channel::new(2).with_bursts(0.01, 8));
+        assert!((0.065..0.08).contains(&bytes), "{bytes}");
+
+        // A burst longer than the FEC can correct loses its packet
+        let mut channel = Channel::new(2).with_bursts(0.002, 24);
+        let report = simulate(encoder(), &mut channel).unwrap();
+        assert_eq!(report.arrived, report.sent);
+        assert!(report.decoded < report.sent, "{report:?}");
+        assert!(report.decoded > report.sent / 2, "{report:?}");
+    }
+
+    #[test]
+    fn drops_packets() {
+        let mut channel = Channel::new(3).with_packet_loss(0.1);
+        let report = simulate(encoder(), &mut channel).unwrap();
+
+        assert!(report.arrived < report.sent, "{report:?}");
+        assert!(report.arrived * 10 > report.sent * 8, "{report:?}");
+        // Nothing that arrives is damaged
+        assert_eq!(report.decoded, report.arrived);
+        assert!(report.completeness < 1.0, "{report:?}");
+    }
+
+    #[test]
+    fn fills_in_erasures_with_the_fec() {
+        let mut channel = Channel::new(4).with_erasure_rate(0.06);
+        let arrival = channel.transmit(&[0xFF; PACKET_SIZE]).unwrap();
+        assert!(!arrival.erasures.is_empty());
+        for (i, byte) in arrival.data.iter().enumerate() {
+            assert_eq!(*byte == 0, arrival.erasures.contains(&i));
+        }
+
+        // About 15 erasures per packet, which the FEC corrects knowing where they are
+        // but not as errors in unknown places
+        let mut channel = Channel::new(4).with_erasure_rate(0.06);
+        let report = simulate(encoder(), &mut channel).unwrap();
+        assert_eq!(report.decoded, report.sent, "{report:?}");
+        assert_eq!(report.completeness, 1.0);
+
+        let mut channel = Channel::new(4).with_erasure_rate(0.06);
+        let mut decoder = Decoder::new();
+        let decoded = encoder()
+            .map(|packet| channel.transmit(&packet.unwrap()).unwrap())
+            .filter(|arrival| decoder.feed(&arrival.data[..]).is_ok())
+            .count();
+        assert!(decoded < report.sent, "{decoded}");
+    }
+
+    #[test]
+    fn keeps_each_kind_of_damage_independent() {
+        let packet: Vec<u8> = (0..=255).collect();
+        let mut base = Channel::new(5).with_bit_error_rate(0.01);
+        let mut lossy = base.clone().with_packet_loss(0.3);
+        let mut erasing = base.clone().with_erasure_rate(0.05);
+
+        let mut lost = 0;
+        for _ in 0..100 {
+            let expected = base.transmit(&packet).unwrap();
+
+            // Packets that arrive are damaged just the same whatever the packet loss
+            match lossy.transmit(&packet) {
+                Some(arrival) => assert_eq!(arrival, expected),
+                None => lost += 1,
+            }
+
+            // Erasures land on top of the same bit errors
+            let arrival = erasing.transmit(&packet).unwrap();
+            for (i, (a, b)) in arrival.data.iter().zip(&expected.data).enumerate() {
+                if !arrival.erasures.contains(&i) {
+                    assert_eq!(a, b);
+                }
+            }
+        }
+
+        assert!((15..45).contains(&lost), "{lost}");
+    }
+}
@@ -0,0 +1,137 @@
+// A tokio-util codec, so packets can be read from and written to async byte
+// streams such as TCP connections, serial ports or pipes.
+
+use std::io;
+
+use arrayvec::ArrayVec;
+use bytes::{Buf, BytesMut};
+use tokio_util::codec;
+
+use crate::{encoder::PACKET_SIZE, framer::Framer, DecodeError};
+
+/// Frames SSDV packets over an async stream with [`tokio_util::codec::Framed`].
+///
+/// Reading finds packets the same way as [`Framer`], by their sync byte and CRC,
+/// skipping anything that isn't one. Writing sends packets as they are.
+pub struct SsdvCodec {
+    packet_length: usize,
+    framer: Framer,
+}
+
+impl SsdvCodec {
+    pub fn new() -> Self {
+        Self {
+            packet_length: PACKET_SIZE,
+            framer: Framer::new(),
+        }
+    }
+
+    /// Creates a codec for packets of `packet_length` bytes, between 64 and 256
+    pub fn with_packet_length(packet_length: usize) -> Result<Self, DecodeError> {
+        return Ok(Self {
+            packet_length,
+            framer: Framer::with_packet_length(packet_length)?,
+        });
+    }
+}
+
+impl Default for SsdvCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl codec::Decoder for SsdvCodec {
+    type Item = ArrayVec<u8, PACKET_SIZE>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while src.has_remaining() {
+            if let Some(packet) = self.framer.push(src.get_u8()) {
+                return Ok(Some(packet));
+            }
+        }
+
+        return Ok(None);
+    }
+}
+
+impl<T: AsRef<[u8]>> codec::Encoder<T> for SsdvCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let packet = packet.as_ref();
+        if packet.len() != self.packet_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "packet is {} bytes, expected {}",
+                    packet.len(),
+                    self.packet_length
+                ),
+            ));
+        }
+
+        dst.extend_from_slice(packet);
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::codec::{Decoder as _, Encoder as _};
+
+    use super::*;
+    use crate::{Encoder, Quality};
+
+    fn packets() -> Vec<ArrayVec<u8, PACKET_SIZE>> {
+        Encoder::new(
+            *b"SOMETH",
+            0,
+            Quality::Q3,
+            include_bytes!("../balloon.jpg").to_vec(),
+        )
+        .with_packet_length(128)
+        .unwrap()
+        .take(4)
+        .map(|packet| packet.unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn decodes_packets_split_across_reads() {
+        let packets = packets();
+        let mut codec = SsdvCodec::with_packet_length(128).unwrap();
+
+        let mut stream = vec![0x00, 0xFF];
+        for packet in &packets {
+            stream.extend_from_slice(packet);
+        }
+
+        let mut decoded = Vec::new();
+        let mut src = BytesMut::new();
+        for chunk in stream.chunks(100) {
+            src.extend_from_slice(chunk);
+            while let Some(packet) = codec.decode(&mut src).unwrap() {
+                decoded.push(packet);
+            }
+        }
+
+        assert_eq!(decoded, packets);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn encodes_packets_as_they_are() {
+        let packet = &packets()[0];
+        let mut codec = SsdvCodec::with_packet_length(128).unwrap();
+
+        let mut dst = BytesMut::new();
+        codec.encode(packet, &mut dst).unwrap();
+        assert_eq!(dst[..], packet[..]);
+
+        let err = codec.encode(&packet[..100], &mut dst).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(dst.len(), 128);
+    }
+}
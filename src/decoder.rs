@@ -1 +1,1063 @@
-pub struct Decoder {}
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    io::{self, Read, Write},
+    sync::OnceLock,
+};
+
+use log::{info, warn};
+
+use crate::{
+    encoder::{
+        crc32, Encoder, HEADER_SIZE, MIN_PACKET_SIZE, PACKET_SIZE, STD_DHT00, STD_DHT01, STD_DHT10,
+        STD_DHT11, STD_DQT0, STD_DQT1,
+    },
+    image::Image,
+    jpeg,
+    receiver::ReceiverMetadata,
+    rs, PacketType, Quality,
+};
+
+/// A packet handed to the [`Decoder`], along with how it was received
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedPacket<'a> {
+    pub data: &'a [u8],
+    pub metadata: Option<ReceiverMetadata>,
+}
+
+impl<'a> ReceivedPacket<'a> {
+    pub fn new(data: &'a [u8], metadata: ReceiverMetadata) -> Self {
+        Self {
+            data,
+            metadata: Some(metadata),
+        }
+    }
+}
+
+impl<'a> From<&'a [u8]> for ReceivedPacket<'a> {
+    fn from(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            metadata: None,
+        }
+    }
+}
+
+impl<'a, const N: usize> From<&'a [u8; N]> for ReceivedPacket<'a> {
+    fn from(data: &'a [u8; N]) -> Self {
+        Self {
+            data,
+            metadata: None,
+        }
+    }
+}
+
+/// Receiver metadata kept for each packet, so a packet fed over and over can't grow without limit
+const MAX_RECEPTIONS: usize = 64;
+
+/// A packet accepted by the decoder, and every station that reported receiving it
+struct Received {
+    header: Header,
+    data: [u8; PACKET_SIZE],
+    receptions: Vec<ReceiverMetadata>,
+}
+
+/// Reassembles an image from the packets produced by an [`Encoder`].
+///
+/// Packets can be fed in any order, and are buffered by packet ID until the
+/// image is rendered. Lost packets leave gaps in the image which are rendered
+/// as flat grey MCUs.
+pub struct Decoder {
+    packet_length: usize,
+    header: Option<Header>,
+    /// Every packet accepted so far, by packet ID
+    packets: BTreeMap<u16, Received>,
+    /// The packets decoded so far, kept until a packet arrives out of order
+    scan: OnceLock<Scan>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            packet_length: PACKET_SIZE,
+            header: None,
+            packets: BTreeMap::new(),
+            scan: OnceLock::new(),
+        }
+    }
+
+    /// Creates a decoder for packets of `packet_length` bytes, between 64 and 256.
+    /// This must match the packet length the image was encoded with.
+    pub fn with_packet_length(packet_length: usize) -> Result<Self, DecodeError> {
+        if !(MIN_PACKET_SIZE..=PACKET_SIZE).contains(&packet_length) {
+            return Err(DecodeError::PacketLength);
+        }
+
+        return Ok(Self {
+            packet_length,
+            ..Self::new()
+        });
+    }
+
+    /// Validates a single packet and buffers it for decoding. Normal packets which fail
+    /// their CRC check are repaired with their FEC bytes where possible.
+    ///
+    /// Every packet fed to a decoder must belong to the same image. Packets
+    /// which have already been received only have their receiver metadata recorded.
+    /// Each distinct report is recorded once, for the first 64 reports of a packet.
+    pub fn feed<'a, P: Into<ReceivedPacket<'a>>>(&mut self, packet: P) -> Result<(), DecodeError> {
+        let ReceivedPacket {
+            data: packet,
+            metadata,
+        } = packet.into();
+
+        if packet.len() < self.packet_length {
+            return Err(DecodeError::Length);
+        }
+
+        let mut data = [0; PACKET_SIZE];
+        data[..self.packet_length].copy_from_slice(&packet[..self.packet_length]);
+
+        let header = match Header::parse(&data, self.packet_length) {
+            Ok(header) => header,
+            Err(err @ (DecodeError::Sync | DecodeError::PacketType | DecodeError::Crc)) => {
+                repair(&mut data, self.packet_length).ok_or(err)?
+            }
+            Err(err) => return Err(err),
+        };
+
+        match &self.header {
+            Some(h) if !h.same_image(&header) => return Err(DecodeError::Image),
+            Some(_) => {}
+            None => {
+                info!("Resolution: {}x{}", header.width, header.height);
+                info!("MCU mode: {}", header.mcu_mode);
+                info!("Quality: {}", header.quality.num());
+                if header.chroma_quality != header.quality {
+                    info!("Chroma quality: {}", header.chroma_quality.num());
+                }
+                self.header = Some(header);
+            }
+        }
+
+        let received = match self.packets.entry(header.packet_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                // Packets after the last one decoded carry on the scan, anything earlier
+                // means decoding again from the start next time the image is needed
+                match self.scan.get_mut() {
+                    Some(scan) if scan.last_packet_id < Some(header.packet_id) => {
+                        scan.feed(&header, &data);
+                    }
+                    _ => {
+                        self.scan.take();
+                    }
+                }
+
+                entry.insert(Received {
+                    header,
+                    data,
+                    receptions: Vec::new(),
+                })
+            }
+        };
+        if let Some(metadata) = metadata {
+            if received.receptions.len() < MAX_RECEPTIONS
+                && !received.receptions.contains(&metadata)
+            {
+                received.receptions.push(metadata);
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Writes the decoder's state so reception of the image can be resumed later with [`Decoder::load`].
+    ///
+    /// The state is made up of the packets received so far, which carry the image's header
+    /// metadata along with them, and the receiver metadata recorded for each.
+    pub fn save<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&STATE_MAGIC)?;
+        writer.write_all(&[STATE_VERSION])?;
+        writer.write_all(&(self.packet_length as u16).to_be_bytes())?;
+        writer.write_all(&(self.packets.len() as u32).to_be_bytes())?;
+
+        for received in self.packets.values() {
+            writer.write_all(&received.data[..self.packet_length])?;
+            let receptions = u16::try_from(received.receptions.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "too many receptions to save")
+            })?;
+            writer.write_all(&receptions.to_be_bytes())?;
+
+            for metadata in &received.receptions {
+                metadata.write(writer)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Restores a decoder from the state written by [`Decoder::save`]
+    pub fn load<R: Read>(reader: &mut R) -> io::Result<Decoder> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+
+        let mut version = [0; 1];
+        reader.read_exact(&mut version)?;
+
+        if magic != STATE_MAGIC || version[0] != STATE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an SSDV decoder state",
+            ));
+        }
+
+        let mut packet_length = [0; 2];
+        reader.read_exact(&mut packet_length)?;
+        let packet_length = u16::from_be_bytes(packet_length) as usize;
+
+        let mut decoder = Decoder::with_packet_length(packet_length).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid packet length in saved state: {packet_length}"),
+            )
+        })?;
+
+        let mut count = [0; 4];
+        reader.read_exact(&mut count)?;
+
+        let mut packet = [0; PACKET_SIZE];
+        let packet = &mut packet[..packet_length];
+        for _ in 0..u32::from_be_bytes(count) {
+            reader.read_exact(packet)?;
+
+            let header = Header::parse(packet, packet_length)
+                .and_then(|header| decoder.feed(&*packet).map(|_| header))
+                .map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid packet in saved state: {err:?}"),
+                    )
+                })?;
+
+            let mut count = [0; 2];
+            reader.read_exact(&mut count)?;
+
+            let receptions = (0..u16::from_be_bytes(count))
+                .map(|_| ReceiverMetadata::read(reader))
+                .collect::<io::Result<Vec<_>>>()?;
+
+            if let Some(received) = decoder.packets.get_mut(&header.packet_id) {
+                received.receptions = receptions;
+            }
+        }
+
+        return Ok(decoder);
+    }
+
+    /// Renders the MCUs decoded so far, or `None` if no packets have been received yet
+    pub fn image(&self) -> Option<Image> {
+        let scan = self.scan()?;
+        let header = &scan.header;
+
+        let dqt = [
+            Encoder::load_standard_dqt(&STD_DQT0, header.quality),
+            Encoder::load_standard_dqt(&STD_DQT1, header.chroma_quality),
+        ];
+
+        let (mcu_width, mcu_height) = header.mcu_size();
+        let mcus_per_row = header.width / mcu_width;
+        let parts = header.ycparts() as usize + 2;
+
+        let mut image = Image::new(header.width, header.height);
+
+        for mcu in 0..header.mcu_count() {
+            let x0 = (mcu % mcus_per_row) * mcu_width;
+            let y0 = (mcu / mcus_per_row) * mcu_height;
+            let blocks = &scan.blocks[mcu as usize * parts..(mcu as usize + 1) * parts];
+
+            let mut samples = [[0u8; 64]; 6];
+            for (i, block) in blocks.iter().enumerate() {
+                let table = if i < parts - 2 { &dqt[0] } else { &dqt[1] };
+                samples[i] = jpeg::decode_block(block, table);
+            }
+
+            let (cb, cr) = (&samples[parts - 2], &samples[parts - 1]);
+
+            for y in 0..mcu_height {
+                for x in 0..mcu_width {
+                    let luma = &samples[(y / 8 * (mcu_width / 8) + x / 8) as usize];
+                    let l = luma[((y % 8) * 8 + x % 8) as usize];
+
+                    // Chroma is upsampled to cover the whole MCU
+                    let c = ((y * 8 / mcu_height) * 8 + x * 8 / mcu_width) as usize;
+
+                    image.set_pixel(x0 + x, y0 + y, jpeg::ycbcr_to_rgb(l, cb[c], cr[c]));
+                }
+            }
+        }
+
+        return Some(image);
+    }
+
+    /// The receiver metadata recorded for each packet received so far, by packet ID
+    pub fn receptions(&self) -> impl Iterator<Item = (u16, &[ReceiverMetadata])> {
+        return self
+            .packets
+            .iter()
+            .map(|(id, r)| (*id, r.receptions.as_slice()));
+    }
+
+    /// Whether every MCU of the image has been decoded
+    pub fn is_complete(&self) -> bool {
+        return self
+            .scan()
+            .is_some_and(|scan| scan.decoded.iter().all(|d| *d));
+    }
+
+    /// Fraction of the image's MCUs decoded so far, from 0 to 1
+    pub fn completeness(&self) -> f64 {
+        let Some(scan) = self.scan() else {
+            return 0.0;
+        };
+
+        let decoded = scan.decoded.iter().filter(|d| **d).count();
+        return decoded as f64 / scan.decoded.len().max(1) as f64;
+    }
+
+    /// Callsign of the station sending the image, once the first packet has been received
+    pub fn callsign(&self) -> Option<String> {
+        return self.header.as_ref().map(|h| h.callsign());
+    }
+
+    /// The sender's id for the image, once the first packet has been received
+    pub fn image_id(&self) -> Option<u8> {
+        return self.header.as_ref().map(|h| h.image_id);
+    }
+
+    /// Width of the image in pixels, once the first packet has been received
+    pub fn width(&self) -> Option<u16> {
+        return self.header.as_ref().map(|h| h.width);
+    }
+
+    /// Height of the image in pixels, once the first packet has been received
+    pub fn height(&self) -> Option<u16> {
+        return self.header.as_ref().map(|h| h.height);
+    }
+
+    /// The buffered packets decoded in packet ID order, decoding them if needed
+    fn scan(&self) -> Option<&Scan> {
+        let header = self.header.as_ref()?;
+
+        return Some(self.scan.get_or_init(|| {
+            let mut scan = Scan::new(header, self.packet_length);
+            for received in self.packets.values() {
+                scan.feed(&received.header, &received.data);
+            }
+            scan
+        }));
+    }
+}
+
+/// Entropy decoder state while working through the packets of an image
+struct Scan {
+    header: Header,
+    packet_length: usize,
+    /// Quantised coefficients of every block in the image, in zigzag order
+    blocks: Vec<[i16; 64]>,
+    /// Which MCUs have been fully decoded
+    decoded: Vec<bool>,
+    /// Blocks of the MCU currently being decoded
+    mcu: [[i16; 64]; 6],
+    synced: bool,
+    state: State,
+    workbits: u32,
+    worklen: u8,
+    needbits: u8,
+    dc: [i16; 3],
+    acpart: u8,
+    mcupart: u8,
+    component: u8,
+    mcu_id: u16,
+    reset_mcu: u16,
+    /// ID of the last packet fed to the scan
+    last_packet_id: Option<u16>,
+}
+
+impl Scan {
+    fn new(header: &Header, packet_length: usize) -> Self {
+        let mcus = header.mcu_count() as usize;
+
+        Self {
+            header: *header,
+            packet_length,
+            blocks: vec![[0; 64]; mcus * (header.ycparts() as usize + 2)],
+            decoded: vec![false; mcus],
+            mcu: [[0; 64]; 6],
+            synced: false,
+            state: State::Huff,
+            workbits: 0,
+            worklen: 0,
+            needbits: 0,
+            dc: [0; 3],
+            acpart: 0,
+            mcupart: 0,
+            component: 0,
+            mcu_id: 0,
+            reset_mcu: 0,
+            last_packet_id: None,
+        }
+    }
+
+    /// Decodes the image data of a single packet. Packets must be fed in packet ID
+    /// order, and carry on from the MCU left over when they immediately follow the
+    /// one decoded before.
+    fn feed(&mut self, header: &Header, packet: &[u8]) {
+        let payload_size = header.packet_type.payload_size(self.packet_length);
+        let payload = &packet[HEADER_SIZE..HEADER_SIZE + payload_size];
+        let continuing = self.synced
+            && self.last_packet_id.map(|id| id.wrapping_add(1)) == Some(header.packet_id);
+        self.last_packet_id = Some(header.packet_id);
+
+        // The start of the payload finishes off the MCU left over from the previous packet
+        if continuing {
+            let (end, stop) = if header.mcu_id == 0xFFFF {
+                (payload.len(), None)
+            } else {
+                (header.mcu_offset as usize, Some(header.mcu_id))
+            };
+
+            if let Err(err) = self.decode(&payload[..end], stop) {
+                self.lost(header, err);
+            }
+        } else {
+            self.synced = false;
+        }
+
+        if header.mcu_id == 0xFFFF {
+            // No MCU starts in this packet, so there is nothing to resync on
+            return;
+        }
+
+        if !continuing {
+            info!(
+                "Resyncing at packet {}, MCU {}",
+                header.packet_id, header.mcu_id
+            );
+        }
+
+        self.reset(header.mcu_id);
+        if let Err(err) = self.decode(&payload[header.mcu_offset as usize..], None) {
+            self.lost(header, err);
+        }
+    }
+
+    /// Gives up on the current MCU after a decoding error, until the next packet resyncs
+    fn lost(&mut self, header: &Header, err: DecodeError) {
+        if header.eoi && err == DecodeError::NoMatch {
+            // The image was truncated, the rest of the last packet is padded with 1 bits
+            info!("Image data ends at MCU {}", self.mcu_id);
+        } else {
+            warn!(
+                "Lost MCU {} in packet {}: {err:?}",
+                self.mcu_id, header.packet_id
+            );
+        }
+
+        self.synced = false;
+    }
+
+    /// Restarts decoding at the first MCU of a packet, which begins with absolute DC values
+    fn reset(&mut self, mcu_id: u16) {
+        self.state = State::Huff;
+        self.workbits = 0;
+        self.worklen = 0;
+        self.needbits = 0;
+        self.dc.fill(0);
+        self.acpart = 0;
+        self.mcupart = 0;
+        self.component = 0;
+        self.mcu_id = mcu_id;
+        self.reset_mcu = mcu_id;
+        self.mcu = [[0; 64]; 6];
+        self.synced = true;
+    }
+
+    /// Decodes image data until it runs out or the MCU `stop` is reached
+    fn decode(&mut self, data: &[u8], stop: Option<u16>) -> Result<(), DecodeError> {
+        if stop == Some(self.mcu_id) {
+            return Ok(());
+        }
+
+        for b in data {
+            if self.mcu_id >= self.mcu_count() {
+                break;
+            }
+
+            self.workbits = (self.workbits << 8) | *b as u32;
+            self.worklen += 8;
+
+            loop {
+                let mcu_id = self.mcu_id;
+
+                match self.process() {
+                    Ok(()) => {}
+                    Err(DecodeError::OutOfBits) => break,
+                    Err(err) => return Err(err),
+                }
+
+                if self.mcu_id != mcu_id {
+                    if stop == Some(self.mcu_id) {
+                        // The rest is padding up to the next MCU
+                        return Ok(());
+                    }
+
+                    if self.mcu_id >= self.mcu_count() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn process(&mut self) -> Result<(), DecodeError> {
+        let ycparts = self.ycparts();
+
+        if self.state == State::Huff {
+            let (symbol, width) = self.dht_lookup()?;
+
+            self.worklen -= width;
+            self.workbits &= (1 << self.worklen) - 1;
+
+            if self.acpart == 0 {
+                // DC
+                self.needbits = symbol;
+                self.state = State::Int;
+            } else if symbol == 0x00 {
+                // EOB -- all remaining AC parts are zero
+                self.acpart = 64;
+            } else if symbol == 0xF0 {
+                // The next 16 AC parts are zero
+                self.acpart += 16;
+            } else {
+                // The next bits are an integer value
+                self.acpart += symbol >> 4;
+                self.needbits = symbol & 0x0F;
+                self.state = State::Int;
+            }
+        } else if self.state == State::Int {
+            if self.worklen < self.needbits {
+                return Err(DecodeError::OutOfBits);
+            }
+
+            let i = int(
+                (self.workbits >> (self.worklen - self.needbits)) as i16,
+                self.needbits,
+            );
+
+            self.worklen -= self.needbits;
+            self.workbits &= (1 << self.worklen) - 1;
+
+            let component = self.component as usize;
+            if self.acpart == 0 {
+                if self.mcu_id == self.reset_mcu && (self.mcupart == 0 || self.mcupart >= ycparts) {
+                    // The first MCU of a packet carries absolute DC values
+                    self.dc[component] = i;
+                } else {
+                    self.dc[component] = self.dc[component].wrapping_add(i);
+                }
+
+                self.mcu[self.mcupart as usize][0] = self.dc[component];
+            } else if self.acpart < 64 {
+                self.mcu[self.mcupart as usize][self.acpart as usize] = i;
+            }
+
+            self.acpart += 1;
+            self.state = State::Huff;
+        }
+
+        if self.acpart >= 64 {
+            self.mcupart += 1;
+
+            if self.mcupart == ycparts + 2 {
+                // Reached the end of this MCU
+                self.store_mcu();
+                self.mcupart = 0;
+                self.mcu_id += 1;
+            }
+
+            self.component = if self.mcupart < ycparts {
+                0
+            } else {
+                self.mcupart - ycparts + 1
+            };
+
+            self.acpart = 0;
+        }
+
+        Ok(())
+    }
+
+    fn store_mcu(&mut self) {
+        let parts = self.ycparts() as usize + 2;
+        let mcu = self.mcu_id as usize;
+
+        for (i, block) in self.mcu.iter_mut().take(parts).enumerate() {
+            self.blocks[mcu * parts + i] = *block;
+            *block = [0; 64];
+        }
+
+        self.decoded[mcu] = true;
+    }
+
+    fn dht_lookup(&self) -> Result<(u8, u8), DecodeError> {
+        let mut code = 0;
+
+        let dht = self.dht();
+        let mut ss = dht[17..].iter();
+
+        for cw in 1..=16 {
+            if cw > self.worklen {
+                return Err(DecodeError::OutOfBits);
+            }
+
+            for _ in 0..dht[cw as usize] {
+                let symbol = ss.next().ok_or(DecodeError::NoMatch)?;
+                if self.workbits >> (self.worklen - cw) == code {
+                    return Ok((*symbol, cw));
+                }
+                code += 1;
+            }
+
+            code <<= 1;
+        }
+
+        return Err(DecodeError::NoMatch);
+    }
+
+    fn dht(&self) -> &[u8] {
+        match (self.acpart, self.component) {
+            (0, 0) => &STD_DHT00,
+            (0, _) => &STD_DHT01,
+            (_, 0) => &STD_DHT10,
+            (_, _) => &STD_DHT11,
+        }
+    }
+
+    fn ycparts(&self) -> u8 {
+        return self.header.ycparts();
+    }
+
+    fn mcu_count(&self) -> u16 {
+        return self.header.mcu_count();
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sign extends a `width` bit JPEG integer
+fn int(bits: i16, width: u8) -> i16 {
+    let b = (1i32 << width) - 1;
+    let bits = bits as i32;
+
+    if bits <= b >> 1 {
+        return -(bits ^ b) as i16;
+    }
+
+    return bits as i16;
+}
+
+/// The fields of an SSDV packet header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Header {
+    pub packet_type: PacketType,
+    pub callsign: u32,
+    pub image_id: u8,
+    pub packet_id: u16,
+    pub width: u16,
+    pub height: u16,
+    pub quality: Quality,
+    pub chroma_quality: Quality,
+    pub eoi: bool,
+    pub mcu_mode: u8,
+    pub mcu_offset: u8,
+    pub mcu_id: u16,
+}
+
+impl Header {
+    /// Parses and validates the header of a `packet_length` byte packet, including its CRC
+    pub fn parse(packet: &[u8], packet_length: usize) -> Result<Header, DecodeError> {
+        if packet.len() < packet_length {
+            return Err(DecodeError::Length);
+        }
+
+        if packet[0] != SYNC {
+            return Err(DecodeError::Sync);
+        }
+
+        let packet_type = PacketType::from_byte(packet[1]).ok_or(DecodeError::PacketType)?;
+
+        let payload_size = packet_type.payload_size(packet_length);
+        let crc_end = HEADER_SIZE + payload_size;
+        let crc = u32::from_be_bytes([
+            packet[crc_end],
+            packet[crc_end + 1],
+            packet[crc_end + 2],
+            packet[crc_end + 3],
+        ]);
+
+        if crc32(&packet[1..crc_end]) != crc {
+            return Err(DecodeError::Crc);
+        }
+
+        let quality = Quality::from_num(((packet[11] >> 3) & 7).wrapping_add(4) & 7).unwrap();
+
+        let header = Header {
+            packet_type,
+            callsign: u32::from_be_bytes([packet[2], packet[3], packet[4], packet[5]]),
+            image_id: packet[6],
+            packet_id: ((packet[7] as u16) << 8) | packet[8] as u16,
+            width: (packet[9] as u16) << 4,
+            height: (packet[10] as u16) << 4,
+            quality,
+            chroma_quality: Header::chroma_quality(quality, packet[11]),
+            eoi: (packet[11] >> 2) & 1 == 1,
+            mcu_mode: packet[11] & 0x03,
+            mcu_offset: packet[12],
+            mcu_id: ((packet[13] as u16) << 8) | packet[14] as u16,
+        };
+
+        if header.width == 0 || header.height == 0 {
+            return Err(DecodeError::Header);
+        }
+
+        if header.mcu_id != 0xFFFF
+            && (header.mcu_id >= header.mcu_count() || header.mcu_offset as usize >= payload_size)
+        {
+            return Err(DecodeError::Header);
+        }
+
+        return Ok(header);
+    }
+
+    /// Decodes the base-40 callsign
+    pub fn callsign(&self) -> String {
+        let mut callsign = String::new();
+        let mut code = self.callsign;
+
+        while code > 0 {
+            let c = match code % 40 {
+                s @ 1..=10 => (b'0' + s as u8 - 1) as char,
+                s @ 14.. => (b'A' + s as u8 - 14) as char,
+                _ => '-',
+            };
+
+            callsign.push(c);
+            code /= 40;
+        }
+
+        return callsign;
+    }
+
+    /// Whether two packets belong to the same image
+    /// The chroma quality, sent by the `chroma-quality` extension as the number of levels
+    /// it's below the quality in the reserved bits of the flags byte. Without the extension
+    /// these bits are ignored, as the reference decoder does.
+    fn chroma_quality(quality: Quality, flags: u8) -> Quality {
+        if cfg!(feature = "chroma-quality") {
+            return Quality::from_num(quality.num().saturating_sub(flags >> 6)).unwrap();
+        }
+
+        return quality;
+    }
+
+    pub fn same_image(&self, other: &Header) -> bool {
+        return self.callsign == other.callsign
+            && self.image_id == other.image_id
+            && self.width == other.width
+            && self.height == other.height
+            && self.quality == other.quality
+            && self.chroma_quality == other.chroma_quality
+            && self.mcu_mode == other.mcu_mode;
+    }
+
+    /// Number of luma blocks in each MCU
+    pub fn ycparts(&self) -> u8 {
+        match self.mcu_mode {
+            0 => 4,
+            1 | 2 => 2,
+            _ => 1,
+        }
+    }
+
+    /// Width and height of each MCU in pixels
+    pub fn mcu_size(&self) -> (u16, u16) {
+        match self.mcu_mode {
+            0 => (16, 16),
+            1 => (8, 16),
+            2 => (16, 8),
+            _ => (8, 8),
+        }
+    }
+
+    pub fn mcu_count(&self) -> u16 {
+        let (w, h) = self.mcu_size();
+        return ((self.width / w) as u32 * (self.height / h) as u32).min(0xFFFF) as u16;
+    }
+}
+
+/// Attempts to correct a damaged packet with its FEC bytes, assuming it is a Normal packet
+pub(crate) fn repair(packet: &mut [u8; PACKET_SIZE], packet_length: usize) -> Option<Header> {
+    return repair_erasures(packet, packet_length, &[]);
+}
+
+/// Like [`repair`], also given the positions in the packet of any bytes known to be lost
+pub(crate) fn repair_erasures(
+    packet: &mut [u8; PACKET_SIZE],
+    packet_length: usize,
+    erasures: &[usize],
+) -> Option<Header> {
+    let mut repaired = *packet;
+    repaired[0] = SYNC;
+    repaired[1] = PacketType::Normal.to_byte();
+
+    // The sync byte isn't covered by the FEC, and the packet type is put back above
+    let erasures: Vec<usize> = erasures
+        .iter()
+        .filter(|pos| (2..packet_length).contains(*pos))
+        .map(|pos| pos - 1)
+        .collect();
+
+    let errors = rs::decode(&mut repaired[1..packet_length], &erasures)?;
+    let header = Header::parse(&repaired, packet_length).ok()?;
+
+    info!("Corrected {errors} bytes in packet {}", header.packet_id);
+    *packet = repaired;
+
+    return Some(header);
+}
+
+pub(crate) const SYNC: u8 = 0x55;
+
+/// Identifies a saved decoder state
+const STATE_MAGIC: [u8; 4] = *b"SSDS";
+/// Format of the saved decoder state, to be bumped if the layout ever changes
+const STATE_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum State {
+    Huff,
+    Int,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DecodeError {
+    /// The packet is shorter than the decoder's packet length
+    Length,
+    /// Packets must be between 64 and 256 bytes long
+    PacketLength,
+    /// The packet does not start with the sync byte
+    Sync,
+    /// The packet type is not Normal or No-FEC
+    PacketType,
+    /// The packet failed its CRC check
+    Crc,
+    /// The packet header contains invalid values
+    Header,
+    /// The packet belongs to a different image
+    Image,
+    /// Reached the end of the packet unexpectedly
+    OutOfBits,
+    /// No match found for huffman table
+    NoMatch,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BALLOON: &[u8] = include_bytes!("../balloon.jpg");
+
+    fn encode(image_id: u8, packet_type: PacketType) -> Vec<Vec<u8>> {
+        return Encoder::new(*b"SOMETH", image_id, Quality::Q3, BALLOON.to_vec())
+            .with_packet_type(packet_type)
+            .map(|packet| packet.unwrap().to_vec())
+            .collect();
+    }
+
+    fn decode(packets: &[Vec<u8>]) -> Decoder {
+        let mut decoder = Decoder::new();
+        for packet in packets {
+            decoder.feed(&packet[..]).unwrap();
+        }
+
+        return decoder;
+    }
+
+    #[test]
+    fn decodes_every_packet() {
+        let decoder = decode(&encode(0, PacketType::NoFEC));
+
+        assert!(decoder.is_complete());
+        assert_eq!(decoder.completeness(), 1.0);
+        assert_eq!(decoder.callsign().as_deref(), Some("SOMETH"));
+        assert_eq!(decoder.image_id(), Some(0));
+
+        let image = decoder.image().unwrap();
+        assert_eq!(Some(image.width()), decoder.width());
+        assert_eq!(Some(image.height()), decoder.height());
+    }
+
+    #[test]
+    fn decodes_in_any_order() {
+        let packets = encode(0, PacketType::NoFEC);
+        let mut reversed = packets.clone();
+        reversed.reverse();
+
+        assert_eq!(decode(&reversed).image(), decode(&packets).image());
+    }
+
+    #[test]
+    fn leaves_gaps_for_lost_packets() {
+        let mut packets = encode(0, PacketType::NoFEC);
+        packets.remove(packets.len() / 2);
+
+        let decoder = decode(&packets);
+        assert!(!decoder.is_complete());
+        assert!(decoder.completeness() > 0.5 && decoder.completeness() < 1.0);
+        assert!(decoder.image().is_some());
+    }
+
+    #[test]
+    fn rejects_damaged_packets() {
+        let mut packet = encode(0, PacketType::NoFEC).remove(0);
+        let mut decoder = Decoder::new();
+
+        assert_eq!(decoder.feed(&packet[..100]), Err(DecodeError::Length));
+
+        packet[20] ^= 0xFF;
+        assert_eq!(decoder.feed(&packet[..]), Err(DecodeError::Crc));
+
+        packet[20] ^= 0xFF;
+        packet[0] = 0;
+        assert_eq!(decoder.feed(&packet[..]), Err(DecodeError::Sync));
+
+        assert!(decoder.image().is_none());
+    }
+
+    #[test]
+    fn repairs_normal_packets() {
+        let packets = encode(0, PacketType::Normal);
+        let mut damaged = packets.clone();
+        for packet in &mut damaged {
+            for i in (0..packet.len()).step_by(16) {
+                packet[i] ^= 0x5A;
+            }
+        }
+
+        assert_eq!(decode(&damaged).image(), decode(&packets).image());
+    }
+
+    #[test]
+    fn rejects_packets_from_other_images() {
+        let mut decoder = decode(&encode(0, PacketType::NoFEC));
+        let other = encode(1, PacketType::NoFEC);
+
+        assert_eq!(decoder.feed(&other[0][..]), Err(DecodeError::Image));
+    }
+
+    #[test]
+    fn saves_and_loads_state() {
+        let mut packets = encode(0, PacketType::NoFEC);
+        packets.truncate(packets.len() / 2);
+        let decoder = decode(&packets);
+
+        let mut state = Vec::new();
+        decoder.save(&mut state).unwrap();
+        assert_eq!(state[..5], *b"SSDS\x01");
+
+        let loaded = Decoder::load(&mut &state[..]).unwrap();
+        assert_eq!(loaded.image(), decoder.image());
+        assert_eq!(loaded.completeness(), decoder.completeness());
+
+        state[4] = 3;
+        assert!(Decoder::load(&mut &state[..]).is_err());
+    }
+
+    #[test]
+    fn records_each_reception_once() {
+        let packets = encode(0, PacketType::NoFEC);
+        let report = |i: u64| ReceiverMetadata {
+            station: Some(format!("M0ABC-{i}")),
+            frequency: Some(434_000_000 + i),
+            ..Default::default()
+        };
+
+        let mut decoder = Decoder::new();
+        for _ in 0..3 {
+            decoder
+                .feed(ReceivedPacket::new(&packets[0], report(0)))
+                .unwrap();
+        }
+        decoder
+            .feed(ReceivedPacket::new(&packets[0], report(1)))
+            .unwrap();
+        decoder.feed(&packets[0][..]).unwrap();
+
+        let receptions: Vec<_> = decoder.receptions().collect();
+        assert_eq!(receptions, [(0, &[report(0), report(1)][..])]);
+
+        // Only the first reports are kept for a packet heard over and over
+        for i in 0..1000 {
+            decoder
+                .feed(ReceivedPacket::new(&packets[1], report(i)))
+                .unwrap();
+        }
+
+        let (_, receptions) = decoder.receptions().nth(1).unwrap();
+        assert_eq!(receptions.len(), MAX_RECEPTIONS);
+        assert_eq!(
+            receptions[MAX_RECEPTIONS - 1],
+            report(MAX_RECEPTIONS as u64 - 1)
+        );
+
+        let mut state = Vec::new();
+        decoder.save(&mut state).unwrap();
+        let loaded = Decoder::load(&mut &state[..]).unwrap();
+        assert!(loaded.receptions().eq(decoder.receptions()));
+    }
+
+    #[test]
+    fn updates_image_as_packets_arrive() {
+        let packets = encode(0, PacketType::NoFEC);
+        let expected = decode(&packets).image();
+
+        // In order, checking progress after each packet, then with the odd packets filled in late
+        let mut decoder = Decoder::new();
+        for (i, packet) in packets.iter().enumerate() {
+            decoder.feed(&packet[..]).unwrap();
+            assert_eq!(
+                decoder.completeness(),
+                decode(&packets[..=i]).completeness()
+            );
+        }
+        assert_eq!(decoder.image(), expected);
+
+        let mut decoder = Decoder::new();
+        for packet in packets.iter().step_by(2) {
+            decoder.feed(&packet[..]).unwrap();
+        }
+        assert!(!decoder.is_complete());
+
+        for packet in packets.iter().skip(1).step_by(2) {
+            decoder.feed(&packet[..]).unwrap();
+        }
+        assert!(decoder.is_complete());
+        assert_eq!(decoder.image(), expected);
+    }
+}
@@ -1,29 +1,23 @@
 // yeah i would probably document this if understood anything going on here
 // check out this if you'd like to learn more though: https://github.com/fsphil/ssdv
 
-use std::backtrace::Backtrace;
-
 use arrayvec::ArrayVec;
-use log::{debug, error, info};
-
-use crate::{JpegMarker, PacketType, Quality};
-
-const PACKET_SIZE: usize = 256;
-const HEADER_SIZE: usize = 15;
-const CRC_SIZE: usize = 4;
-const FEC_SIZE: usize = 32;
-const PAYLOAD_SIZE: usize = PACKET_SIZE - HEADER_SIZE - CRC_SIZE;
-const CRCDATA_SIZE: usize = HEADER_SIZE + PAYLOAD_SIZE - 1;
-
-/// APP0 header data
-const APP0: [u8; 14] = [
-    0x4A, 0x46, 0x49, 0x46, 0x00, 0x01, 0x01, 0x01, 0x00, 0x48, 0x00, 0x48, 0x00, 0x00,
-];
-
-/// SOS header data
-const SOS: [u8; 10] = [0x03, 0x01, 0x00, 0x02, 0x11, 0x03, 0x11, 0x00, 0x3F, 0x00];
-
-const STD_DQT0: [u8; 65] = [
+use log::{error, info, warn};
+
+use crate::{
+    interleave::{Interleaver, ScanComponent},
+    rs, JpegMarker, PacketType, Quality,
+};
+
+/// Maximum (and default) packet length
+pub(crate) const PACKET_SIZE: usize = 256;
+/// Minimum packet length
+pub(crate) const MIN_PACKET_SIZE: usize = 64;
+pub(crate) const HEADER_SIZE: usize = 15;
+pub(crate) const CRC_SIZE: usize = 4;
+pub(crate) const FEC_SIZE: usize = rs::NROOTS;
+
+pub(crate) const STD_DQT0: [u8; 65] = [
     0x00, 0x10, 0x0C, 0x0C, 0x0E, 0x0C, 0x0A, 0x10, 0x0E, 0x0E, 0x0E, 0x12, 0x12, 0x10, 0x14, 0x18,
     0x28, 0x1A, 0x18, 0x16, 0x16, 0x18, 0x32, 0x24, 0x26, 0x1E, 0x28, 0x3A, 0x34, 0x3E, 0x3C, 0x3A,
     0x34, 0x38, 0x38, 0x40, 0x48, 0x5C, 0x4E, 0x40, 0x44, 0x58, 0x46, 0x38, 0x38, 0x50, 0x6E, 0x52,
@@ -31,7 +25,7 @@ const STD_DQT0: [u8; 65] = [
     0x64,
 ];
 
-const STD_DQT1: [u8; 65] = [
+pub(crate) const STD_DQT1: [u8; 65] = [
     0x01, 0x12, 0x12, 0x12, 0x16, 0x16, 0x16, 0x30, 0x1A, 0x1A, 0x30, 0x64, 0x42, 0x38, 0x42, 0x64,
     0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64,
     0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64, 0x64,
@@ -40,17 +34,17 @@ const STD_DQT1: [u8; 65] = [
 ];
 
 /* Standard Huffman tables */
-const STD_DHT00: [u8; 29] = [
+pub(crate) const STD_DHT00: [u8; 29] = [
     0x00, 0x00, 0x01, 0x05, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B,
 ];
 
-const STD_DHT01: [u8; 29] = [
+pub(crate) const STD_DHT01: [u8; 29] = [
     0x01, 0x00, 0x03, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00,
     0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B,
 ];
 
-const STD_DHT10: [u8; 179] = [
+pub(crate) const STD_DHT10: [u8; 179] = [
     0x10, 0x00, 0x02, 0x01, 0x03, 0x03, 0x02, 0x04, 0x03, 0x05, 0x05, 0x04, 0x04, 0x00, 0x00, 0x01,
     0x7D, 0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61,
     0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1,
@@ -65,7 +59,7 @@ const STD_DHT10: [u8; 179] = [
     0xF8, 0xF9, 0xFA,
 ];
 
-const STD_DHT11: [u8; 179] = [
+pub(crate) const STD_DHT11: [u8; 179] = [
     0x11, 0x00, 0x02, 0x01, 0x02, 0x04, 0x04, 0x03, 0x04, 0x07, 0x05, 0x04, 0x04, 0x00, 0x01, 0x02,
     0x77, 0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61,
     0x71, 0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52,
@@ -80,17 +74,29 @@ const STD_DHT11: [u8; 179] = [
     0xF8, 0xF9, 0xFA,
 ];
 
-pub struct Encoder {
+/// `I` is where the image data comes from, which only needs naming for an encoder that has
+/// to be `Send` (see [`Encoder::new_send`])
+pub struct Encoder<I = Box<dyn Iterator<Item = u8>>> {
     state: State,
     callsign: u32,
     image_id: u8,
     quality: Quality,
-    image: Box<dyn Iterator<Item = u8>>,
+    /// Quality of the chroma components, no higher than `quality`
+    chroma_quality: Quality,
+    packet_type: PacketType,
+    pub(crate) packet_length: usize,
+    flush_truncated: bool,
+    truncated: bool,
+    image: I,
+    /// More image data is still to come after `image` runs out, so running out isn't the end
+    pub(crate) input_pending: bool,
     dtbl0: [u8; 65],
     dtbl1: [u8; 65],
     outbits: u32,
     outlen: u8,
-    out: ArrayVec<u8, PAYLOAD_SIZE>,
+    /// Encoded image data for the current packet. Anything past the end of the payload
+    /// didn't fit and is carried over to the next packet.
+    out: ArrayVec<u8, PACKET_SIZE>,
     out_stuff: bool,
     skip: usize,
     marker: u16,
@@ -105,6 +111,24 @@ pub struct Encoder {
     reset_mcu: u32,
     next_reset_mcu: u32,
     grayscale: bool,
+    /// Send colour images as grayscale, with the chroma coefficients dropped
+    force_grayscale: bool,
+    components: u8,
+    /// Component ids from the frame header, in the order their blocks appear in each MCU
+    component_ids: [u8; 3],
+    /// The DQT table id used by each component
+    component_dqt: [u8; 3],
+    /// The DC and AC DHT table ids used by each component
+    component_dht: [[u8; 3]; 2],
+    /// Horizontal (high nibble) and vertical sampling factors of each component
+    component_sampling: [u8; 3],
+    /// The sampling factors aren't supported by SSDV, so the components need resampling
+    resample: bool,
+    /// The components of the first scan, for an image being buffered up to be interleaved
+    scan: ArrayVec<usize, 3>,
+    buffered: Vec<u8>,
+    /// The buffered image coded again as a single interleaved scan, read in place of `image`
+    interleaved: std::vec::IntoIter<u8>,
     component: u8,
     ycparts: u8,
     workbits: u32,
@@ -115,8 +139,9 @@ pub struct Encoder {
     mcu_mode: u8,
     mcu_id: u16,
     mcu_count: u16,
-    sdqt: [Option<Vec<u8>>; 2],
-    sdht: [[Option<Vec<u8>>; 2]; 2],
+    /// Quantisation tables from the source image, with 8-bit tables widened to 16 bits
+    sdqt: [Option<Vec<u16>>; 4],
+    sdht: [[Option<Vec<u8>>; 4]; 2],
     dri: u16,
     packet_mcu_id: u16,
     packet_mcu_offset: u8,
@@ -130,15 +155,89 @@ impl Encoder {
         I: IntoIterator<Item = u8>,
         <I as IntoIterator>::IntoIter: 'static,
     {
-        let dtbl0 = Self::load_standard_dqt(&STD_DQT0, quality);
-        let dtbl1 = Self::load_standard_dqt(&STD_DQT1, quality);
+        Self::with_image(
+            callsign.into(),
+            image_id,
+            quality,
+            Box::new(image.into_iter()),
+        )
+    }
+
+    fn encode_callsign(callsign: &[u8]) -> u32 {
+        let mut x: u32 = 0;
+
+        for c in callsign.iter().rev() {
+            x *= 40;
+            if *c >= b'A' && *c <= b'Z' {
+                x += (c - b'A' + 14) as u32;
+            } else if *c >= b'a' && *c <= b'z' {
+                x += (c - b'a' + 14) as u32;
+            } else if *c >= b'0' && *c <= b'9' {
+                x += (c - b'0' + 1) as u32;
+            }
+        }
+
+        return x;
+    }
+
+    pub(crate) fn load_standard_dqt(table: &[u8; 65], quality: Quality) -> [u8; 65] {
+        let scale_factor = quality.scale_factor();
+        let mut out: [u8; 65] = [0; 65];
+
+        out[0] = table[0];
+        for (i, b) in table.iter().copied().enumerate().skip(1) {
+            let mut byte: u32 = (b as u32 * scale_factor as u32 + 50) / 100;
+            byte = byte.clamp(1, 255);
+
+            out[i] = byte as u8;
+        }
+
+        return out;
+    }
+}
+
+impl Encoder<Box<dyn Iterator<Item = u8> + Send>> {
+    /// Like [`Encoder::new`], but for an image iterator that's `Send`, so the encoder can be
+    /// moved between threads. An [`EncoderStream`](crate::EncoderStream) needs one of these.
+    pub fn new_send<C, I>(callsign: C, image_id: u8, quality: Quality, image: I) -> Self
+    where
+        C: Into<ArrayVec<u8, 6>>,
+        I: IntoIterator<Item = u8>,
+        <I as IntoIterator>::IntoIter: Send + 'static,
+    {
+        Self::with_image(
+            callsign.into(),
+            image_id,
+            quality,
+            Box::new(image.into_iter()),
+        )
+    }
+
+    /// Continues the image with more data. Only for once the image data so far has run out,
+    /// as it replaces what's left of it.
+    #[cfg(feature = "futures")]
+    pub(crate) fn push_input(&mut self, data: Vec<u8>) {
+        self.image = Box::new(data.into_iter());
+    }
+}
+
+impl<I: Iterator<Item = u8>> Encoder<I> {
+    fn with_image(callsign: ArrayVec<u8, 6>, image_id: u8, quality: Quality, image: I) -> Self {
+        let dtbl0 = Encoder::load_standard_dqt(&STD_DQT0, quality);
+        let dtbl1 = Encoder::load_standard_dqt(&STD_DQT1, quality);
 
         Self {
             state: State::Marker,
-            callsign: Encoder::encode_callsign(&callsign.into()),
+            callsign: Encoder::encode_callsign(&callsign),
             image_id,
             quality,
-            image: Box::new(image.into_iter()),
+            chroma_quality: quality,
+            packet_type: PacketType::NoFEC,
+            packet_length: PACKET_SIZE,
+            flush_truncated: false,
+            truncated: false,
+            image,
+            input_pending: false,
             dtbl0,
             dtbl1,
             outbits: 0,
@@ -158,6 +257,16 @@ impl Encoder {
             reset_mcu: 0,
             next_reset_mcu: 0,
             grayscale: false,
+            force_grayscale: false,
+            components: 0,
+            component_ids: [0; 3],
+            component_dqt: [0; 3],
+            component_dht: [[0; 3]; 2],
+            component_sampling: [0; 3],
+            resample: false,
+            scan: ArrayVec::new(),
+            buffered: Vec::new(),
+            interleaved: Vec::new().into_iter(),
             component: 0,
             ycparts: 0,
             workbits: 0,
@@ -168,8 +277,8 @@ impl Encoder {
             mcu_count: 0,
             mcu_id: 0,
             mcu_mode: 0,
-            sdqt: [None, None],
-            sdht: [[None, None], [None, None]],
+            sdqt: [None, None, None, None],
+            sdht: [[None, None, None, None], [None, None, None, None]],
             dri: 0,
             packet_mcu_id: 0,
             packet_mcu_offset: 0,
@@ -177,36 +286,59 @@ impl Encoder {
         }
     }
 
-    fn encode_callsign(callsign: &[u8]) -> u32 {
-        let mut x: u32 = 0;
+    /// Sets the type of packets produced, No-FEC by default
+    pub fn with_packet_type(mut self, packet_type: PacketType) -> Self {
+        self.packet_type = packet_type;
+        return self;
+    }
 
-        for c in callsign.into_iter().rev() {
-            x *= 40;
-            if *c >= b'A' && *c <= b'Z' {
-                x += (c - b'A' + 14) as u32;
-            } else if *c >= b'a' && *c <= b'z' {
-                x += (c - b'a' + 14) as u32;
-            } else if *c >= b'0' && *c <= b'9' {
-                x += (c - b'0' + 1) as u32;
-            }
+    /// Sets the length of each packet, between 64 and 256 bytes (the default).
+    /// Shorter packets suit links like LoRa, at the cost of more header overhead.
+    pub fn with_packet_length(mut self, packet_length: usize) -> Result<Self, EncodeError> {
+        if !(MIN_PACKET_SIZE..=PACKET_SIZE).contains(&packet_length) {
+            return Err(EncodeError::PacketLength);
         }
 
-        return x;
+        self.packet_length = packet_length;
+        return Ok(self);
     }
 
-    fn load_standard_dqt(table: &[u8; 65], quality: Quality) -> [u8; 65] {
-        let scale_factor = quality.scale_factor();
-        let mut out: [u8; 65] = [0; 65];
-
-        out[0] = table[0];
-        for (i, b) in table.iter().copied().enumerate().skip(1) {
-            let mut byte: u32 = (b as u32 * scale_factor as u32 + 50) / 100;
-            byte = byte.clamp(1, 255);
+    /// If the image data ends part way through, sends the MCUs encoded so far as a final
+    /// packet with the EOI flag set, rather than ending with [`EncodeError::OutOfBits`].
+    /// This lets a partly written image from a camera still be transmitted.
+    pub fn with_flush_truncated(mut self, flush: bool) -> Self {
+        self.flush_truncated = flush;
+        return self;
+    }
 
-            out[i] = byte as u8;
+    /// Sets the quality of the chroma components separately, so more of each packet can go
+    /// to the luma. By default they use the same quality as the luma.
+    ///
+    /// This is an extension to SSDV, only available with the `chroma-quality` feature. SSDV
+    /// has no field for it, so it's sent as the number of levels below the quality in the two
+    /// reserved bits at the top of the flags byte, limiting the chroma quality to at most 3
+    /// levels below the quality. Other decoders, including the reference one, ignore these
+    /// bits and will decode the image with the chroma at the wrong scale.
+    #[cfg(feature = "chroma-quality")]
+    pub fn with_chroma_quality(mut self, quality: Quality) -> Result<Self, EncodeError> {
+        let Some(below) = self.quality.num().checked_sub(quality.num()) else {
+            return Err(EncodeError::ChromaQuality);
+        };
+
+        if below > 3 {
+            return Err(EncodeError::ChromaQuality);
         }
 
-        return out;
+        self.chroma_quality = quality;
+        self.dtbl1 = Encoder::load_standard_dqt(&STD_DQT1, quality);
+        return Ok(self);
+    }
+
+    /// Sends colour images as grayscale, replacing the chroma of each MCU with empty blocks.
+    /// This saves a good share of the packets on a marginal link.
+    pub fn with_grayscale(mut self, grayscale: bool) -> Self {
+        self.force_grayscale = grayscale;
+        return self;
     }
 
     fn outbits(&mut self, bits: u16, len: u8) -> Result<(), EncodeError> {
@@ -216,7 +348,7 @@ impl Encoder {
             self.outlen += len;
         }
 
-        while self.outlen >= 8 && self.out_len() > 0 {
+        while self.outlen >= 8 && !self.out.is_full() {
             let b = self.outbits >> (self.outlen - 8);
 
             self.out.push(b as u8);
@@ -239,11 +371,25 @@ impl Encoder {
         use JpegMarker as J;
 
         match self.marker.into() {
-            J::Sof0 | J::Sos | J::Dri | J::Dht | J::Dqt => {
+            J::Sof0 | J::Sof1 | J::Sos | J::Dri | J::Dht | J::Dqt => {
                 self.marker_data.clear();
                 self.state = State::MarkerData;
+
+                if self.marker_len == 0 {
+                    return self.have_marker_data();
+                }
             }
             J::Sof2 => return Err(EncodeError::Progressive),
+            J::Sof3
+            | J::Sof5
+            | J::Sof6
+            | J::Sof7
+            | J::Sof9
+            | J::Sof10
+            | J::Sof11
+            | J::Sof13
+            | J::Sof14
+            | J::Sof15 => return Err(EncodeError::CodingProcess),
             J::Eoi => self.state = State::Eoi,
             J::Rst0 | J::Rst1 | J::Rst2 | J::Rst3 | J::Rst4 | J::Rst5 | J::Rst6 | J::Rst7 => {
                 self.dc.fill(0);
@@ -269,7 +415,13 @@ impl Encoder {
         use JpegMarker as J;
 
         match self.marker.into() {
-            J::Sof0 => {
+            // Extended sequential (SOF1) images only differ from baseline in allowing 12-bit
+            // samples, which are rejected below, and more tables
+            J::Sof0 | J::Sof1 => {
+                if self.marker_data.len() < 6 {
+                    return Err(EncodeError::MarkerLen);
+                }
+
                 self.width = ((self.marker_data[3] as u16) << 8) | self.marker_data[4] as u16;
                 self.height = ((self.marker_data[1] as u16) << 8) | self.marker_data[2] as u16;
 
@@ -285,11 +437,21 @@ impl Encoder {
                     return Err(EncodeError::Components);
                 }
 
+                if self.marker_data.len() < 6 + self.marker_data[5] as usize * 3 {
+                    return Err(EncodeError::MarkerLen);
+                }
+
+                self.components = self.marker_data[5];
+
                 if self.width > 4080 || self.height > 4080 {
                     return Err(EncodeError::TooLarge);
                 }
 
-                if (self.width & 0x0F != 0) || self.width & 0x0F != 0 {
+                if self.width == 0
+                    || self.height == 0
+                    || (self.width & 0x0F != 0)
+                    || (self.height & 0x0F != 0)
+                {
                     return Err(EncodeError::InvalidResolution);
                 }
 
@@ -304,45 +466,55 @@ impl Encoder {
                         dq[1] >> 4
                     );
 
-                    // The first (Y) component must have a factor of 2x2, 2x1, 1x2, or 1x1
-                    if i == 0 {
-                        match dq[1] {
-                            0x22 => {
-                                self.mcu_mode = 0;
-                                self.ycparts = 4;
-                            }
-                            0x12 => {
-                                self.mcu_mode = 1;
-                                self.ycparts = 2;
-                            }
-                            0x21 => {
-                                self.mcu_mode = 2;
-                                self.ycparts = 2;
-                            }
-                            0x11 => {
-                                self.mcu_mode = 3;
-                                self.ycparts = 1;
-                            }
-                            _ => return Err(EncodeError::SamplingFactor),
-                        }
-                    } else if dq[1] != 0x11 {
+                    if dq[2] > 3 {
+                        return Err(EncodeError::TableId);
+                    }
+
+                    self.component_ids[i as usize] = dq[0];
+                    self.component_dqt[i as usize] = dq[2];
+
+                    if !(1..=4).contains(&(dq[1] >> 4)) || !(1..=4).contains(&(dq[1] & 0x0F)) {
                         return Err(EncodeError::SamplingFactor);
                     }
+
+                    self.component_sampling[i as usize] = dq[1];
                 }
 
                 if self.marker_data[5] == 1 {
                     self.grayscale = true;
                     self.mcu_mode = 2;
                     self.ycparts = 2;
+                } else {
+                    // The first (Y) component must have a factor of 2x2, 2x1, 1x2, or 1x1, and the
+                    // others 1x1. Anything else is resampled to the closest of these.
+                    let [y, cb, cr] = self.component_sampling;
+                    let sampling =
+                        if matches!(y, 0x22 | 0x12 | 0x21 | 0x11) && cb == 0x11 && cr == 0x11 {
+                            y
+                        } else {
+                            let h = if y >> 4 >= 2 * (cb >> 4) { 2 } else { 1 };
+                            let v = if y & 0x0F >= 2 * (cb & 0x0F) { 2 } else { 1 };
+                            info!("Resampling to a sampling factor of {h}x{v}");
+
+                            self.resample = true;
+                            (h << 4) | v
+                        };
+
+                    (self.mcu_mode, self.ycparts) = match sampling {
+                        0x22 => (0, 4),
+                        0x12 => (1, 2),
+                        0x21 => (2, 2),
+                        _ => (3, 1),
+                    };
                 }
 
-                let blocks: usize = match self.mcu_mode {
-                    0 => (self.width >> 4) * (self.height >> 4),
-                    1 => (self.width >> 4) * (self.height >> 3),
-                    2 => (self.width >> 3) * (self.height >> 4),
-                    3 => (self.width >> 3) * (self.height >> 3),
-                    _ => unreachable!(),
-                } as usize;
+                let (width, height) = (self.width as usize, self.height as usize);
+                let blocks = match self.mcu_mode {
+                    0 => (width >> 4) * (height >> 4),
+                    1 => (width >> 4) * (height >> 3),
+                    2 => (width >> 3) * (height >> 4),
+                    _ => (width >> 3) * (height >> 3),
+                };
 
                 info!("MCU blocks: {blocks}");
 
@@ -353,28 +525,20 @@ impl Encoder {
                 self.mcu_count = blocks as u16;
             }
             J::Sos => {
-                info!("Components: {}", self.marker_data[0]);
-
-                if self.marker_data[0] != 1 && self.marker_data[0] != 3 {
-                    return Err(EncodeError::Components);
-                }
-
-                for i in 0..self.marker_data[0] {
-                    let dh = &self.marker_data[i as usize * 2 + 1..];
-                    info!("Component {} DHT: {}", dh[0], dh[1]);
-                }
-
-                // Verify all of the DQT and DHT tables were loaded
-                if self.sdqt[0].is_none() || (self.marker_data[0] > 1 && self.sdqt[1].is_none()) {
-                    return Err(EncodeError::Dqt);
-                }
-
-                if self.sdht[0][0].is_none()
-                    || (self.marker_data[0] > 1 && self.sdht[0][1].is_none())
-                    || self.sdht[1][0].is_none()
-                    || (self.marker_data[0] > 1 && self.sdht[1][1].is_none())
+                let scan = self.scan_components()?;
+
+                // Anything other than a single scan covering every component in frame header
+                // order, or with sampling factors that need changing, is buffered up and coded
+                // again as one
+                if self.resample
+                    || scan.len() < self.components as usize
+                    || scan.iter().enumerate().any(|(i, c)| i != *c)
                 {
-                    return Err(EncodeError::Dht);
+                    info!("Image is not a single interleaved scan, buffering it");
+
+                    self.scan = scan;
+                    self.state = State::Buffer;
+                    return Ok(());
                 }
 
                 // The SOS data is followed by the image data
@@ -382,7 +546,11 @@ impl Encoder {
                 return Ok(());
             }
             J::Dht => {
-                while self.marker_data.len() > 0 {
+                while !self.marker_data.is_empty() {
+                    if self.marker_data.len() < 17 {
+                        return Err(EncodeError::MarkerLen);
+                    }
+
                     let mut len = 17;
                     for i in 1..=16 {
                         len += self.marker_data[i] as usize;
@@ -396,33 +564,48 @@ impl Encoder {
                     let data = self.marker_data.drain(0..len);
                     let drained = Vec::from_iter(data);
 
-                    match tag {
-                        0x00 => self.sdht[0][0] = Some(drained),
-                        0x01 => self.sdht[0][1] = Some(drained),
-                        0x10 => self.sdht[1][0] = Some(drained),
-                        0x11 => self.sdht[1][1] = Some(drained),
-                        _ => unreachable!(),
+                    // The high nibble is the class (0 for DC, 1 for AC), the low nibble the table id
+                    match (tag >> 4, tag & 0x0F) {
+                        (class @ 0..=1, id @ 0..=3) => {
+                            self.sdht[class as usize][id as usize] = Some(drained)
+                        }
+                        _ => return Err(EncodeError::TableId),
                     }
                 }
             }
             J::Dqt => {
-                while self.marker_data.len() > 0 {
-                    if self.marker_data.len() < 65 {
-                        return Err(EncodeError::MarkerLen);
-                    }
-
+                while !self.marker_data.is_empty() {
+                    // The high nibble is the precision (0 for 8-bit, 1 for 16-bit), the low nibble the id
                     let tag = self.marker_data[0];
-                    let data = self.marker_data.drain(0..65);
-                    let drained = Vec::from_iter(data);
+                    let (precision, id) = (tag >> 4, tag & 0x0F);
+                    if precision > 1 || id > 3 {
+                        return Err(EncodeError::TableId);
+                    }
 
-                    match tag {
-                        0x00 => self.sdqt[0] = Some(drained),
-                        0x01 => self.sdqt[1] = Some(drained),
-                        _ => unreachable!(),
+                    let len = 1 + 64 * (precision as usize + 1);
+                    if self.marker_data.len() < len {
+                        return Err(EncodeError::MarkerLen);
                     }
+
+                    let data = self.marker_data.drain(0..len).skip(1);
+                    let table = if precision == 0 {
+                        data.map(|b| b as u16).collect()
+                    } else {
+                        let bytes = Vec::from_iter(data);
+                        bytes
+                            .chunks(2)
+                            .map(|b| ((b[0] as u16) << 8) | b[1] as u16)
+                            .collect()
+                    };
+
+                    self.sdqt[id as usize] = Some(table);
                 }
             }
             J::Dri => {
+                if self.marker_data.len() < 2 {
+                    return Err(EncodeError::MarkerLen);
+                }
+
                 self.dri = ((self.marker_data[0] as u16) << 8) + (self.marker_data[1] as u16);
                 info!("Reset interval: {} blocks", self.dri);
             }
@@ -433,6 +616,204 @@ impl Encoder {
         Ok(())
     }
 
+    /// Reads the components in a SOS marker, returning their indexes in the frame header
+    fn scan_components(&mut self) -> Result<ArrayVec<usize, 3>, EncodeError> {
+        if self.marker_data.is_empty()
+            || self.marker_data.len() < 1 + self.marker_data[0] as usize * 2
+        {
+            return Err(EncodeError::MarkerLen);
+        }
+
+        info!("Components: {}", self.marker_data[0]);
+
+        if self.marker_data[0] == 0 || self.marker_data[0] > self.components {
+            return Err(EncodeError::Components);
+        }
+
+        let mut scan = ArrayVec::new();
+        for i in 0..self.marker_data[0] {
+            let dh = &self.marker_data[i as usize * 2 + 1..];
+            info!("Component {} DHT: {}", dh[0], dh[1]);
+
+            // Look up which frame header component this is by its id
+            let Some(c) = self.component_ids[..self.components as usize]
+                .iter()
+                .position(|id| *id == dh[0])
+            else {
+                return Err(EncodeError::Components);
+            };
+
+            if scan.contains(&c) {
+                return Err(EncodeError::Components);
+            }
+
+            if dh[1] >> 4 > 3 || dh[1] & 0x0F > 3 {
+                return Err(EncodeError::TableId);
+            }
+
+            self.component_dht[0][c] = dh[1] >> 4;
+            self.component_dht[1][c] = dh[1] & 0x0F;
+
+            // Verify the DQT and DHT tables used by the component were loaded
+            if self.sdqt[self.component_dqt[c] as usize].is_none() {
+                return Err(EncodeError::Dqt);
+            }
+
+            if self.sdht[0][self.component_dht[0][c] as usize].is_none()
+                || self.sdht[1][self.component_dht[1][c] as usize].is_none()
+            {
+                return Err(EncodeError::Dht);
+            }
+
+            scan.push(c);
+        }
+
+        return Ok(scan);
+    }
+
+    /// Takes the buffered rest of the image, made up of the first scan and any following it,
+    /// and replaces it with a single interleaved scan coded with the standard huffman tables.
+    /// The components are resampled first if SSDV doesn't support their sampling factors.
+    fn interleave_scans(&mut self) -> Result<(), EncodeError> {
+        use JpegMarker as J;
+
+        let mut scan = std::mem::take(&mut self.scan);
+        let data = std::mem::take(&mut self.buffered);
+        let mut interleaver = Interleaver::new(
+            &self.component_sampling[..self.components as usize],
+            self.width,
+            self.height,
+        );
+        let mut pos = 0;
+
+        'scans: loop {
+            let decoded = {
+                let components: ArrayVec<ScanComponent, 3> = scan
+                    .iter()
+                    .map(|c| ScanComponent {
+                        index: *c,
+                        dc: self.sdht[0][self.component_dht[0][*c] as usize]
+                            .as_deref()
+                            .unwrap_or(&[]),
+                        ac: self.sdht[1][self.component_dht[1][*c] as usize]
+                            .as_deref()
+                            .unwrap_or(&[]),
+                    })
+                    .collect();
+
+                interleaver.decode_scan(&data[pos..], &components, self.dri)
+            };
+
+            match decoded {
+                Ok(len) => pos += len,
+                Err(EncodeError::OutOfBits) if self.flush_truncated => {
+                    warn!("Image data ended part way through a scan, the rest is left blank");
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+
+            // Handle the markers up to the next scan
+            loop {
+                // Skip any padding up to the next marker
+                while pos < data.len() && data[pos] != 0xFF {
+                    pos += 1;
+                }
+                while pos + 1 < data.len() && data[pos + 1] == 0xFF {
+                    pos += 1;
+                }
+
+                if pos + 2 > data.len() {
+                    if self.flush_truncated {
+                        break 'scans;
+                    }
+
+                    return Err(EncodeError::OutOfBits);
+                }
+
+                self.marker = ((data[pos] as u16) << 8) | data[pos + 1] as u16;
+                pos += 2;
+
+                match self.marker.into() {
+                    J::Eoi => break 'scans,
+                    J::Rst0
+                    | J::Rst1
+                    | J::Rst2
+                    | J::Rst3
+                    | J::Rst4
+                    | J::Rst5
+                    | J::Rst6
+                    | J::Rst7 => {
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                if pos + 2 > data.len() {
+                    return Err(EncodeError::OutOfBits);
+                }
+
+                // The length includes its own two bytes
+                let len = ((data[pos] as usize) << 8) | data[pos + 1] as usize;
+                let Some(len) = len.checked_sub(2) else {
+                    return Err(EncodeError::MarkerLen);
+                };
+
+                pos += 2;
+                if pos + len > data.len() {
+                    return Err(EncodeError::OutOfBits);
+                }
+
+                self.marker_data = data[pos..pos + len].to_vec();
+                pos += len;
+
+                match self.marker.into() {
+                    J::Sos => {
+                        scan = self.scan_components()?;
+                        continue 'scans;
+                    }
+                    J::Dht | J::Dqt | J::Dri => self.have_marker_data()?,
+                    J::Sof2 => return Err(EncodeError::Progressive),
+                    _ => {}
+                }
+            }
+        }
+
+        if self.resample {
+            let dqt: ArrayVec<&[u16], 3> = (0..self.components as usize)
+                .map(|c| {
+                    self.sdqt[self.component_dqt[c] as usize]
+                        .as_deref()
+                        .unwrap_or(&[1; 64])
+                })
+                .collect();
+
+            interleaver.resample(self.mcu_mode, &dqt);
+        }
+
+        self.interleaved = interleaver.encode()?.into_iter();
+
+        // The new scan uses the standard huffman tables, with the chroma components sharing a pair
+        self.sdht = [
+            [
+                Some(STD_DHT00.to_vec()),
+                Some(STD_DHT01.to_vec()),
+                None,
+                None,
+            ],
+            [
+                Some(STD_DHT10.to_vec()),
+                Some(STD_DHT11.to_vec()),
+                None,
+                None,
+            ],
+        ];
+        self.component_dht = [[0, 1, 1], [0, 1, 1]];
+        self.dri = 0;
+
+        return Ok(());
+    }
+
     fn process(&mut self) -> Result<(), EncodeError> {
         if self.state == State::Huff {
             if self.mcupart == 0 && self.acpart == 0 && self.next_reset_mcu > self.reset_mcu {
@@ -444,17 +825,25 @@ impl Encoder {
             if self.acpart == 0 {
                 // DC
                 if symbol == 0x00 {
-                    // No change in DC from the last block
+                    // No change in DC from the last block. The adjusted value can still
+                    // change if the DC values were reset by a restart marker.
+                    let c = self.component as usize;
+                    let adc = self.aadj(self.dc[c]);
+
                     if self.reset_mcu == self.mcu_id as u32
                         && (self.mcupart == 0 || self.mcupart >= self.ycparts)
                     {
-                        let _ = self.out_jpeg_int(0, self.adc[self.component as usize]);
+                        let _ = self.out_jpeg_int(0, adc);
                     } else {
-                        let _ = self.out_jpeg_int(0, 0);
+                        let _ = self.out_jpeg_int(0, adc - self.adc[c]);
                     }
 
+                    self.adc[c] = adc;
+
                     // Skip to the next AC part immedietly
                     self.acpart += 1;
+                } else if symbol > 15 {
+                    return Err(EncodeError::InvalidData);
                 } else {
                     // DC value follows, 'symbol' bits wide
                     self.state = State::Int;
@@ -477,6 +866,11 @@ impl Encoder {
                     self.acrle = symbol >> 4;
                     self.acpart += self.acrle;
                     self.needbits = symbol & 0x0F;
+
+                    if self.acpart > 63 {
+                        // The run of zeros goes past the end of the block
+                        return Err(EncodeError::InvalidData);
+                    }
                 }
             }
 
@@ -576,12 +970,18 @@ impl Encoder {
                     self.next_reset_mcu = self.mcu_id as u32;
                     self.packet_mcu_id = self.mcu_id;
                     self.packet_mcu_offset =
-                        (PAYLOAD_SIZE - self.out_len() + ((self.outlen as usize + 7) / 8)) as u8;
+                        (self.out.len() + (self.outlen as usize).div_ceil(8)) as u8;
                 }
 
-                if self.dri > 0 && self.mcu_id > 0 && self.mcu_id % self.dri == 0 {
+                if self.dri > 0 && self.mcu_id > 0 && self.mcu_id.is_multiple_of(self.dri) {
+                    // Stop here until the RST marker has been read, which resets the block state
                     self.state = State::Marker;
-                    return Ok(());
+
+                    if self.out_len() == 0 {
+                        return Err(EncodeError::BufferFull);
+                    }
+
+                    return Err(EncodeError::OutOfBits);
                 }
             }
 
@@ -606,6 +1006,10 @@ impl Encoder {
         let mut code = 0;
 
         let dht = self.sdht();
+        if dht.len() < 17 {
+            return Err(EncodeError::Dht);
+        }
+
         let mut ss = dht[17..].iter();
 
         for cw in 1..=16 {
@@ -613,11 +1017,11 @@ impl Encoder {
                 return Err(EncodeError::OutOfBits);
             }
 
-            for _ in (1..=dht[cw as usize]).rev() {
+            for _ in 0..dht[cw as usize] {
+                let symbol = ss.next().ok_or(EncodeError::NoMatch)?;
                 if self.workbits >> (self.worklen - cw) == code {
-                    return Ok((*ss.next().unwrap(), cw));
+                    return Ok((*symbol, cw));
                 }
-                ss.next();
                 code += 1;
             }
 
@@ -638,11 +1042,11 @@ impl Encoder {
         let mut code = 0;
 
         let dht = self.ddht();
-        let mut ss = dht[17..].iter().peekable();
+        let mut ss = dht[17..].iter();
 
         for cw in 1..=16 {
-            for n in (1..=dht[cw as usize]).rev() {
-                if ss.next().unwrap() == &symbol {
+            for _ in 0..dht[cw as usize] {
+                if ss.next() == Some(&symbol) {
                     *bits = code;
                     *width = cw;
                     return Ok(());
@@ -703,6 +1107,10 @@ impl Encoder {
     }
 
     fn aadj(&self, i: isize) -> isize {
+        if self.drop_chroma() {
+            return 0;
+        }
+
         let sdqt = self.sdqt();
         let ddqt = self.ddqt();
 
@@ -714,6 +1122,10 @@ impl Encoder {
     }
 
     fn badj(&self, i: isize) -> isize {
+        if self.drop_chroma() {
+            return 0;
+        }
+
         let sdqt = self.sdqt();
         let ddqt = self.ddqt();
 
@@ -724,26 +1136,34 @@ impl Encoder {
         }
     }
 
-    fn sdqt(&self) -> u8 {
-        return self.sdqt[if self.component > 0 { 1 } else { 0 }]
+    /// The source chroma is still decoded to find where each block ends, but every coefficient
+    /// comes out as zero
+    fn drop_chroma(&self) -> bool {
+        return self.force_grayscale && self.component > 0;
+    }
+
+    fn sdqt(&self) -> u16 {
+        // The tables are checked for at the start of the scan, so this never falls back
+        return self.sdqt[self.component_dqt[self.component as usize] as usize]
             .as_deref()
-            .map(|sdqt| sdqt[1 + self.acpart as usize])
-            .unwrap();
+            .and_then(|sdqt| sdqt.get(self.acpart as usize))
+            .copied()
+            .unwrap_or_else(|| self.ddqt());
     }
 
-    fn ddqt(&self) -> u8 {
+    fn ddqt(&self) -> u16 {
         if self.component > 0 {
-            return self.dtbl1[1 + self.acpart as usize];
+            return self.dtbl1[1 + self.acpart as usize] as u16;
         } else {
-            return self.dtbl0[1 + self.acpart as usize];
+            return self.dtbl0[1 + self.acpart as usize] as u16;
         }
     }
 
     fn sdht(&self) -> &[u8] {
-        return self.sdht[if self.acpart > 0 { 1 } else { 0 }]
-            [if self.component > 0 { 1 } else { 0 }]
-        .as_deref()
-        .unwrap_or(&[]);
+        let class = if self.acpart > 0 { 1 } else { 0 };
+        let id = self.component_dht[class][self.component as usize];
+
+        return self.sdht[class][id as usize].as_deref().unwrap_or(&[]);
     }
 
     fn ddht(&self) -> &[u8] {
@@ -755,20 +1175,186 @@ impl Encoder {
         }
     }
 
-    fn out_len(&self) -> usize {
-        return self.out.capacity() - self.out.len();
+    /// Completes the MCU being encoded with empty blocks, for when the image data ends part way through it
+    fn finish_mcu(&mut self) -> Result<(), EncodeError> {
+        if self.mcupart == 0 && self.acpart == 0 {
+            return Ok(());
+        }
+
+        while self.mcupart < self.ycparts + 2 {
+            if self.out_len() == 0 {
+                return Err(EncodeError::BufferFull);
+            }
+
+            if self.mcupart < self.ycparts {
+                self.component = 0;
+            } else {
+                self.component = self.mcupart - self.ycparts + 1;
+            }
+
+            if self.acpart == 0 {
+                // Keep the DC value of the last block
+                if self.reset_mcu == self.mcu_id as u32
+                    && (self.mcupart == 0 || self.mcupart >= self.ycparts)
+                {
+                    let _ = self.out_jpeg_int(0, self.adc[self.component as usize]);
+                } else {
+                    let _ = self.out_jpeg_int(0, 0);
+                }
+
+                self.acpart = 1;
+            }
+
+            // EOB
+            let _ = self.out_jpeg_int(0, 0);
+
+            self.mcupart += 1;
+            self.acpart = 0;
+            self.accrle = 0;
+        }
+
+        self.mcupart = 0;
+        self.mcu_id += 1;
+
+        return Ok(());
     }
-}
 
-impl Iterator for Encoder {
-    type Item = Result<[u8; PACKET_SIZE], EncodeError>;
+    /// Sends out everything encoded so far, for when the image data ends part way through the scan
+    fn flush(&mut self) -> Result<ArrayVec<u8, PACKET_SIZE>, EncodeError> {
+        match self.finish_mcu() {
+            Ok(()) => {}
+            Err(EncodeError::BufferFull) => return Ok(self.packet(false)),
+            Err(err) => return Err(err),
+        }
 
-    fn next(&mut self) -> Option<Self::Item> {
+        let _ = self.outbits_sync();
+        if self.out.len() > self.payload_size() {
+            // The last few bytes didn't fit in this packet
+            return Ok(self.packet(false));
+        }
+
+        self.state = State::Eoi;
+        return Ok(self.packet(true));
+    }
+
+    /// Assembles a packet from the image data encoded so far
+    fn packet(&mut self, eoi: bool) -> ArrayVec<u8, PACKET_SIZE> {
+        let mut mcu_id = self.packet_mcu_id;
+        let mut mcu_offset = self.packet_mcu_offset;
+
+        let payload_size = self.payload_size();
+
+        if mcu_offset != 0xFF && mcu_offset as usize >= payload_size {
+            // The first MCU begins in the next packet, not this one
+            mcu_id = 0xFFFF;
+            mcu_offset = 0xFF;
+            self.packet_mcu_offset -= payload_size as u8;
+        } else {
+            // Clear the MCU data for the next packet
+            self.packet_mcu_id = 0xFFFF;
+            self.packet_mcu_offset = 0xFF;
+        }
+
+        let callsign = self.callsign.to_be_bytes();
+
+        let mut output = [0; PACKET_SIZE];
+        output[0] = 0x55; // Sync
+        output[1] = self.packet_type.to_byte();
+        output[2] = callsign[0];
+        output[3] = callsign[1];
+        output[4] = callsign[2];
+        output[5] = callsign[3];
+        output[6] = self.image_id;
+        output[7] = (self.packet_id >> 8) as u8;
+        output[8] = (self.packet_id & 0xFF) as u8;
+        output[9] = (self.width >> 4) as u8; // Width / 16
+        output[10] = (self.height >> 4) as u8; // Height / 16
+                                               // Reserved, apart from the chroma offset of the chroma-quality extension
+        output[11] |= (self.quality.num() - self.chroma_quality.num()) << 6;
+        output[11] |= ((self.quality.num().wrapping_sub(4)) & 7) << 3; // Quality level
+        output[11] |= (eoi as u8) << 2; // EOI flag (1 bit)
+        output[11] |= self.mcu_mode & 0x03; // MCU mode (2 bits)
+        output[12] = mcu_offset;
+        output[13] = (mcu_id >> 8) as u8;
+        output[14] = (mcu_id & 0xFF) as u8;
+
+        let free = self.out_len();
+        let drain = self.out.drain(0..payload_size - free);
+        for (i, b) in drain.enumerate() {
+            output[i + HEADER_SIZE] = b;
+        }
+        let _ = self.outbits(0, 0);
+
+        let mut l: u8 = 0x00;
+        for n in 0..free {
+            let i = HEADER_SIZE + payload_size - free + n;
+            if self.truncated {
+                // Noise here would be decoded as more MCUs, whereas 1 bits never match a huffman code
+                output[i] = 0xFF;
+            } else {
+                l = l.wrapping_mul(254).wrapping_add(45); // A very simple PRNG for noise whitening
+                output[i] = l;
+            }
+        }
+
+        let crc_end = HEADER_SIZE + payload_size;
+        let crc = crc32(&output[1..crc_end]);
+        output[crc_end..crc_end + CRC_SIZE].copy_from_slice(&crc.to_be_bytes());
+
+        if self.packet_type == PacketType::Normal {
+            // Shorter packets use a shortened code, the sync byte isn't covered
+            let fec_start = crc_end + CRC_SIZE;
+            let parity = rs::encode(&output[1..fec_start]);
+            output[fec_start..fec_start + FEC_SIZE].copy_from_slice(&parity);
+        }
+
+        let mut output = ArrayVec::from(output);
+        output.truncate(self.packet_length);
+
+        self.packet_id = self.packet_id.wrapping_add(1);
+
+        return output;
+    }
+
+    /// Encodes the image data read so far, returning a packet if one fills up before it runs out
+    fn process_bits(&mut self) -> Option<Result<ArrayVec<u8, PACKET_SIZE>, EncodeError>> {
+        let err = loop {
+            if let Err(err) = self.process() {
+                break err;
+            }
+        };
+
+        match err {
+            EncodeError::BufferFull | EncodeError::Eoi => {
+                let eoi = err == EncodeError::Eoi;
+                if eoi {
+                    self.state = State::Eoi;
+                }
+
+                return Some(Ok(self.packet(eoi)));
+            }
+            EncodeError::OutOfBits => return None,
+            err => return Some(Err(err)),
+        }
+    }
+
+    /// Encodes image data until the next packet is ready
+    pub(crate) fn encode_packet(
+        &mut self,
+    ) -> Option<Result<ArrayVec<u8, PACKET_SIZE>, EncodeError>> {
         if self.state == State::Eoi {
             return None;
         }
 
-        while let Some(b) = self.image.next() {
+        // Finish off any image data left over from the last packet first, so it doesn't run
+        // on into a restart marker
+        if matches!(self.state, State::Huff | State::Int) {
+            if let Some(packet) = self.process_bits() {
+                return Some(packet);
+            }
+        }
+
+        while let Some(b) = self.interleaved.next().or_else(|| self.image.next()) {
             if self.skip > 0 {
                 self.skip -= 1;
                 continue;
@@ -796,7 +1382,12 @@ impl Iterator for Encoder {
                     self.needbits -= 8;
 
                     if self.needbits == 0 {
-                        self.marker_len -= 2;
+                        // The length includes its own two bytes
+                        let Some(marker_len) = self.marker_len.checked_sub(2) else {
+                            return Some(Err(EncodeError::MarkerLen));
+                        };
+
+                        self.marker_len = marker_len;
                         if let Err(err) = self.have_marker() {
                             return Some(Err(err));
                         }
@@ -818,85 +1409,68 @@ impl Iterator for Encoder {
                     self.workbits = (self.workbits << 8) | b as u32;
                     self.worklen += 8;
 
-                    let mut r = self.process();
-                    while let Ok(_) = r {
-                        r = self.process();
+                    if let Some(packet) = self.process_bits() {
+                        return Some(packet);
                     }
+                }
+                State::Buffer => {
+                    self.buffered.push(b);
+                    self.buffered.extend(self.image.by_ref());
+                }
+                State::Eoi => return None,
+            }
+        }
 
-                    if matches!(r, Err(EncodeError::BufferFull | EncodeError::Eoi)) {
-                        let mut mcu_id = self.packet_mcu_id;
-                        let mut mcu_offset = self.packet_mcu_offset;
+        if self.input_pending {
+            return Some(Err(EncodeError::OutOfBits));
+        }
 
-                        if mcu_offset != 0xFF && mcu_offset >= PAYLOAD_SIZE as u8 {
-                            // The first MCU begins in the next packet, not this one
-                            mcu_id = 0xFFFF;
-                            mcu_offset = 0xFF;
-                            self.packet_mcu_offset -= PAYLOAD_SIZE as u8;
-                        } else {
-                            // Clear the MCU data for the next packet
-                            self.packet_mcu_id = 0xFFFF;
-                            self.packet_mcu_offset = 0xFF;
-                        }
+        if self.state == State::Buffer {
+            if let Err(err) = self.interleave_scans() {
+                return Some(Err(err));
+            }
 
-                        let callsign = self.callsign.to_be_bytes();
-
-                        let mut output = [0; PACKET_SIZE];
-                        output[0] = 0x55; // Sync
-                        output[1] = 0x67; // No-FEC mode
-                        output[2] = callsign[0];
-                        output[3] = callsign[1];
-                        output[4] = callsign[2];
-                        output[5] = callsign[3];
-                        output[6] = self.image_id;
-                        output[7] = (self.packet_id >> 8) as u8;
-                        output[8] = (self.packet_id & 0xFF) as u8;
-                        output[9] = (self.width >> 4) as u8; // Width / 16
-                        output[10] = (self.height >> 4) as u8; // Height / 16
-                        output[11] |= ((self.quality.num().wrapping_sub(4)) & 7) << 3; // Quality level
-                        output[11] |= (if matches!(r, Err(EncodeError::Eoi)) {
-                            1
-                        } else {
-                            0
-                        }) << 2; // EOI flag (1 bit)
-                        output[11] |= self.mcu_mode & 0x03; // MCU mode (2 bits)
-                        output[12] = mcu_offset;
-                        output[13] = (mcu_id >> 8) as u8;
-                        output[14] = (mcu_id & 0xFF) as u8;
-
-                        let free = self.out_len();
-                        let drain = self.out.drain(0..);
-                        for (i, b) in drain.enumerate() {
-                            output[i + HEADER_SIZE] = b;
-                        }
-                        let _ = self.outbits(0, 0);
+            self.state = State::Huff;
+            return self.encode_packet();
+        }
 
-                        let mut l: u8 = 0x00;
-                        for n in 0..free {
-                            let i = HEADER_SIZE + PAYLOAD_SIZE - free + n;
-                            l = l.wrapping_mul(254).wrapping_add(45); // A very simple PRNG for noise whitening
-                            output[i] = l;
-                        }
+        let in_scan = self.mcu_id > 0 || matches!(self.state, State::Huff | State::Int);
+        if self.flush_truncated && in_scan {
+            if !self.truncated {
+                warn!(
+                    "Image data ended at MCU {}, flushing what was encoded",
+                    self.mcu_id
+                );
+                self.truncated = true;
+            }
 
-                        let crc = crc32(&output[1..=CRCDATA_SIZE]);
+            return Some(self.flush());
+        }
 
-                        output[1 + CRCDATA_SIZE..].copy_from_slice(&crc.to_be_bytes());
+        return Some(Err(EncodeError::OutOfBits));
+    }
 
-                        self.packet_id += 1;
+    fn out_len(&self) -> usize {
+        return self.payload_size().saturating_sub(self.out.len());
+    }
 
-                        if matches!(r, Err(EncodeError::Eoi)) {
-                            self.state = State::Eoi;
-                        }
+    fn payload_size(&self) -> usize {
+        return self.packet_type.payload_size(self.packet_length);
+    }
+}
 
-                        return Some(Ok(output));
-                    } else if !matches!(r, Err(EncodeError::OutOfBits)) {
-                        return Some(Err(r.unwrap_err()));
-                    }
-                }
-                State::Eoi => return None,
-            }
+impl<I: Iterator<Item = u8>> Iterator for Encoder<I> {
+    type Item = Result<ArrayVec<u8, PACKET_SIZE>, EncodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let packet = self.encode_packet();
+
+        // There's no recovering from an error, so don't try to encode any further
+        if let Some(Err(_)) = packet {
+            self.state = State::Eoi;
         }
 
-        return Some(Err(EncodeError::OutOfBits));
+        return packet;
     }
 }
 
@@ -923,7 +1497,7 @@ fn encode_int(mut value: isize) -> (isize, u8) {
     return (bits, width as u8);
 }
 
-fn crc32(data: &[u8]) -> u32 {
+pub(crate) fn crc32(data: &[u8]) -> u32 {
     let mut crc = 0xFFFFFFFF;
 
     for b in data {
@@ -948,19 +1522,23 @@ enum State {
     MarkerData,
     Huff,
     Int,
+    /// Collecting the rest of the image to interleave its scans
+    Buffer,
     Eoi,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum EncodeError {
     Progressive,
+    /// The image must use the baseline or extended sequential huffman coding process
+    CodingProcess,
     /// The image must have a precision of 8
     Precision,
     /// The image must have 1 or 3 components (Y'Cb'Cr)
     Components,
     /// Maximum image is 4080x4080
     TooLarge,
-    /// The image dimensions mus be a multiple of 16
+    /// The image dimensions must be a non-zero multiple of 16
     InvalidResolution,
     // Component's sampling factor is not supported
     SamplingFactor,
@@ -972,6 +1550,10 @@ pub enum EncodeError {
     Dht,
     /// The image has an invalid marker len
     MarkerLen,
+    /// A DQT or DHT table uses a table id other than 0-3, or an unknown DQT precision or DHT class
+    TableId,
+    /// The image data is corrupt
+    InvalidData,
     /// Reached the end of the image unexpecdedly
     OutOfBits,
     /// Reached the end of the image
@@ -979,4 +1561,395 @@ pub enum EncodeError {
     /// No match found for huffman table
     NoMatch,
     BufferFull,
+    /// Packets must be between 64 and 256 bytes long
+    PacketLength,
+    /// The chroma quality must be between the quality and 3 levels below it. Only returned
+    /// with the `chroma-quality` feature.
+    ChromaQuality,
+    /// Reading the image from its source failed
+    Read,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decoder::Header, Decoder};
+
+    const BALLOON: &[u8] = include_bytes!("../balloon.jpg");
+
+    fn encode(image: &[u8]) -> Vec<Vec<u8>> {
+        return Encoder::new(*b"SOMETH", 0, Quality::Q3, image.to_vec())
+            .map(|packet| packet.unwrap().to_vec())
+            .collect();
+    }
+
+    /// Updates the CRC of a No-FEC packet after changing it
+    fn update_crc(packet: &mut [u8]) {
+        let crc = crc32(&packet[1..PACKET_SIZE - CRC_SIZE]);
+        packet[PACKET_SIZE - CRC_SIZE..].copy_from_slice(&crc.to_be_bytes());
+    }
+
+    /// The first error encoding `image` ends with
+    fn encode_error(image: Vec<u8>) -> EncodeError {
+        return Encoder::new(*b"SOMETH", 0, Quality::Q3, image)
+            .find_map(|packet| packet.err())
+            .unwrap();
+    }
+
+    /// Offsets of the segments before the image data, up to and including the SOS
+    fn segments(image: &[u8]) -> Vec<(usize, JpegMarker)> {
+        let mut segments = Vec::new();
+        let mut i = 2;
+        loop {
+            let marker = u16::from_be_bytes([image[i], image[i + 1]]).into();
+            segments.push((i, marker));
+            if marker == JpegMarker::Sos {
+                return segments;
+            }
+
+            i += 2 + u16::from_be_bytes([image[i + 2], image[i + 3]]) as usize;
+        }
+    }
+
+    /// Offset of the first `marker` segment before the image data
+    fn find_marker(image: &[u8], marker: JpegMarker) -> usize {
+        return segments(image)
+            .into_iter()
+            .find(|(_, m)| *m == marker)
+            .unwrap()
+            .0;
+    }
+
+    /// The balloon image with the length of its first `marker` segment replaced
+    fn with_marker_len(marker: JpegMarker, len: u16) -> Vec<u8> {
+        let mut image = BALLOON.to_vec();
+        let i = find_marker(&image, marker);
+        image[i + 2..i + 4].copy_from_slice(&len.to_be_bytes());
+        return image;
+    }
+
+    fn decode(packets: &[Vec<u8>]) -> Decoder {
+        let mut decoder = Decoder::new();
+        for packet in packets {
+            decoder.feed(&packet[..]).unwrap();
+        }
+
+        return decoder;
+    }
+
+    #[test]
+    fn takes_images_that_arent_send() {
+        let image = std::rc::Rc::new(BALLOON.to_vec());
+        let bytes = (0..image.len()).map(move |i| image[i]);
+
+        let packets = Encoder::new(*b"SOMETH", 0, Quality::Q3, bytes);
+        assert!(packets
+            .map(|packet| packet.unwrap().to_vec())
+            .eq(encode(BALLOON)));
+    }
+
+    #[test]
+    fn flushes_truncated_images() {
+        // Cut off early in the scan, part way through and near the end
+        for len in [0x200, 0x8000, 0x10000, BALLOON.len() - 0x400] {
+            let image = BALLOON[..len].to_vec();
+
+            let mut encoder = Encoder::new(*b"SOMETH", 0, Quality::Q3, image.clone());
+            assert!(encoder.any(|packet| packet == Err(EncodeError::OutOfBits)));
+
+            let packets: Vec<_> = Encoder::new(*b"SOMETH", 0, Quality::Q3, image)
+                .with_flush_truncated(true)
+                .map(|packet| packet.unwrap().to_vec())
+                .collect();
+
+            let (last, rest) = packets.split_last().unwrap();
+            assert!(Header::parse(last, PACKET_SIZE).unwrap().eoi);
+            for packet in rest {
+                assert!(!Header::parse(packet, PACKET_SIZE).unwrap().eoi);
+            }
+
+            let decoder = decode(&packets);
+            assert!(decoder.completeness() > 0.0);
+            assert!(decoder.image().is_some());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_marker_lengths() {
+        // Too short to even cover the length itself
+        assert_eq!(
+            encode_error(with_marker_len(JpegMarker::Dqt, 1)),
+            EncodeError::MarkerLen
+        );
+
+        // Too short for the tables or components they hold
+        for (marker, len) in [
+            (JpegMarker::Dqt, 3),
+            (JpegMarker::Sof0, 7),
+            (JpegMarker::Sof0, 10),
+            (JpegMarker::Dht, 12),
+            (JpegMarker::Sos, 4),
+        ] {
+            assert_eq!(
+                encode_error(with_marker_len(marker, len)),
+                EncodeError::MarkerLen,
+                "{marker:?}"
+            );
+        }
+
+        let mut image = BALLOON.to_vec();
+        image.splice(2..2, [0xFF, 0xDD, 0x00, 0x02]);
+        assert_eq!(encode_error(image), EncodeError::MarkerLen);
+    }
+
+    #[test]
+    fn rejects_out_of_range_table_ids() {
+        let dqt = find_marker(BALLOON, JpegMarker::Dqt);
+        let dht = find_marker(BALLOON, JpegMarker::Dht);
+        let sof = find_marker(BALLOON, JpegMarker::Sof0);
+        let sos = find_marker(BALLOON, JpegMarker::Sos);
+
+        for (i, tag) in [
+            // DQT table id 4 and precision 2
+            (dqt + 4, 0x04),
+            (dqt + 4, 0x20),
+            // DHT table id 4 and class 2
+            (dht + 4, 0x04),
+            (dht + 4, 0x20),
+            // The first component's DQT table and DHT tables
+            (sof + 12, 0x04),
+            (sos + 6, 0x40),
+            (sos + 6, 0x04),
+        ] {
+            let mut image = BALLOON.to_vec();
+            image[i] = tag;
+            assert_eq!(encode_error(image), EncodeError::TableId, "{i:#x}");
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_segments() {
+        for marker in [
+            JpegMarker::Dqt,
+            JpegMarker::Sof0,
+            JpegMarker::Dht,
+            JpegMarker::Sos,
+        ] {
+            let i = find_marker(BALLOON, marker);
+
+            // Ending in the marker, its length and its data
+            for len in [i + 1, i + 3, i + 6] {
+                let image = BALLOON[..len].to_vec();
+                assert_eq!(encode_error(image.clone()), EncodeError::OutOfBits);
+
+                // There's nothing encoded yet to flush
+                let mut encoder =
+                    Encoder::new(*b"SOMETH", 0, Quality::Q3, image).with_flush_truncated(true);
+                assert_eq!(encoder.next(), Some(Err(EncodeError::OutOfBits)));
+            }
+        }
+    }
+
+    #[test]
+    fn uses_the_tables_components_reference() {
+        // Move every table from ids 0 and 1 to 2 and 3. Each of the balloon's DQT and DHT
+        // segments holds a single table.
+        let mut image = BALLOON.to_vec();
+        for (i, marker) in segments(BALLOON) {
+            if matches!(marker, JpegMarker::Dqt | JpegMarker::Dht) {
+                image[i + 4] += 2;
+            }
+        }
+
+        let sof = find_marker(&image, JpegMarker::Sof0);
+        let sos = find_marker(&image, JpegMarker::Sos);
+        for c in 0..3 {
+            image[sof + 12 + c * 3] += 2;
+            image[sos + 6 + c * 2] += 0x22;
+        }
+
+        assert_eq!(encode(&image), encode(BALLOON));
+    }
+
+    #[test]
+    fn reads_16_bit_quantisation_tables() {
+        // Widen each of the balloon's 8-bit tables to 16 bits, starting from the last so the
+        // offsets of the others don't move
+        let mut image = BALLOON.to_vec();
+        for (i, marker) in segments(BALLOON).into_iter().rev() {
+            if marker != JpegMarker::Dqt {
+                continue;
+            }
+
+            let mut segment = vec![0xFF, 0xDB, 0x00, 0x83, 0x10 | image[i + 4]];
+            for b in &image[i + 5..i + 69] {
+                segment.extend([0x00, *b]);
+            }
+
+            image.splice(i..i + 69, segment);
+        }
+
+        assert_eq!(encode(&image), encode(BALLOON));
+    }
+
+    #[test]
+    fn accepts_extended_sequential_images() {
+        let sof = find_marker(BALLOON, JpegMarker::Sof0);
+        let mut image = BALLOON.to_vec();
+        image[sof + 1] = 0xC1;
+        assert_eq!(encode(&image), encode(BALLOON));
+
+        // Only 8-bit samples are supported
+        image[sof + 4] = 12;
+        assert_eq!(encode_error(image), EncodeError::Precision);
+    }
+
+    /// A flat coloured 32x32 image with the given sampling factors, in a single scan
+    fn flat_image(sampling: [u8; 3]) -> Vec<u8> {
+        let mut image = vec![0xFF, 0xD8];
+        for table in [&STD_DQT0, &STD_DQT1] {
+            image.extend([0xFF, 0xDB, 0x00, 0x43]);
+            image.extend(table);
+        }
+
+        image.extend([0xFF, 0xC0, 0x00, 0x11, 0x08, 0x00, 0x20, 0x00, 0x20, 0x03]);
+        for (i, s) in sampling.iter().enumerate() {
+            image.extend([i as u8 + 1, *s, (i > 0) as u8]);
+        }
+
+        for table in [&STD_DHT00[..], &STD_DHT01, &STD_DHT10, &STD_DHT11] {
+            image.extend([0xFF, 0xC4, 0x00, table.len() as u8 + 2]);
+            image.extend(table);
+        }
+
+        image.extend([
+            0xFF, 0xDA, 0x00, 0x0C, 0x03, 0x01, 0x00, 0x02, 0x11, 0x03, 0x11, 0x00, 0x3F, 0x00,
+        ]);
+
+        let mut interleaver = Interleaver::new(&sampling, 32, 32);
+        for (c, dc) in [20, -10, 15].into_iter().enumerate() {
+            interleaver.fill(c, dc);
+        }
+        image.extend(interleaver.encode().unwrap());
+
+        return image;
+    }
+
+    #[test]
+    fn resamples_to_the_closest_mcu_mode() {
+        let reference = decode(&encode(&flat_image([0x11, 0x11, 0x11])));
+        let reference = reference.image().unwrap();
+
+        for (sampling, mcu_mode) in [
+            // 4:4:4 and 4:2:2 are sent as they are
+            ([0x11, 0x11, 0x11], 3),
+            ([0x21, 0x11, 0x11], 2),
+            // 4:4:4 with every factor doubled
+            ([0x22, 0x22, 0x22], 3),
+            // 4:1:1
+            ([0x41, 0x11, 0x11], 2),
+            // Chroma at half the height of the luma, rather than half the width
+            ([0x22, 0x21, 0x21], 1),
+            ([0x14, 0x11, 0x11], 1),
+            ([0x42, 0x11, 0x11], 0),
+        ] {
+            let packets = encode(&flat_image(sampling));
+            for packet in &packets {
+                let header = Header::parse(packet, PACKET_SIZE).unwrap();
+                assert_eq!(header.mcu_mode, mcu_mode, "{sampling:x?}");
+            }
+
+            let image = decode(&packets).image().unwrap();
+            for (a, b) in image.pixels().iter().zip(reference.pixels()) {
+                assert!(a.abs_diff(*b) <= 2, "{sampling:x?}: {a} and {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn sends_colour_images_as_grayscale() {
+        let colour = encode(BALLOON);
+        let grayscale: Vec<_> = Encoder::new(*b"SOMETH", 0, Quality::Q3, BALLOON.to_vec())
+            .with_grayscale(true)
+            .map(|packet| packet.unwrap().to_vec())
+            .collect();
+
+        assert!(grayscale.len() < colour.len());
+
+        let decoder = decode(&grayscale);
+        assert!(decoder.is_complete());
+
+        let image = decoder.image().unwrap();
+        for rgb in image.pixels().chunks(3) {
+            assert!(rgb[0].abs_diff(rgb[1]) <= 1 && rgb[1].abs_diff(rgb[2]) <= 1);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "chroma-quality")]
+    fn sends_the_chroma_quality() {
+        let encoder = || Encoder::new(*b"SOMETH", 0, Quality::Q5, BALLOON.to_vec());
+
+        // Above the quality, and too far below it
+        for quality in [Quality::Q6, Quality::Q1] {
+            assert_eq!(
+                encoder().with_chroma_quality(quality).err(),
+                Some(EncodeError::ChromaQuality)
+            );
+        }
+
+        let full: Vec<_> = encoder().map(|packet| packet.unwrap().to_vec()).collect();
+        let packets: Vec<_> = encoder()
+            .with_chroma_quality(Quality::Q2)
+            .unwrap()
+            .map(|packet| packet.unwrap().to_vec())
+            .collect();
+        assert!(packets.len() < full.len());
+
+        for packet in &packets {
+            assert_eq!(packet[11] >> 6, 3);
+
+            let header = Header::parse(packet, PACKET_SIZE).unwrap();
+            assert_eq!(header.quality, Quality::Q5);
+            assert_eq!(header.chroma_quality, Quality::Q2);
+        }
+
+        // Mean difference of the pixels from those of the full quality image
+        let reference = decode(&full).image().unwrap();
+        let difference = |image: crate::Image| {
+            let pixels = image.pixels().iter().zip(reference.pixels());
+            let total: u64 = pixels.map(|(a, b)| a.abs_diff(*b) as u64).sum();
+            total as f64 / reference.pixels().len() as f64
+        };
+
+        let decoder = decode(&packets);
+        assert!(decoder.is_complete());
+        let error = difference(decoder.image().unwrap());
+
+        // Without the chroma quality the chroma is decoded at the wrong scale
+        let mut ignored = packets.clone();
+        for packet in &mut ignored {
+            packet[11] &= 0x3F;
+            update_crc(packet);
+        }
+        let wrong_scale = difference(decode(&ignored).image().unwrap());
+
+        assert!(
+            error < 3.0 && wrong_scale > error * 2.0,
+            "{error} {wrong_scale}"
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "chroma-quality"))]
+    fn ignores_the_reserved_bits() {
+        let mut packet = encode(BALLOON).remove(0);
+        assert_eq!(packet[11] >> 6, 0);
+
+        packet[11] |= 0xC0;
+        update_crc(&mut packet);
+
+        let header = Header::parse(&packet, PACKET_SIZE).unwrap();
+        assert_eq!(header.chroma_quality, header.quality);
+    }
 }
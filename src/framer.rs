@@ -0,0 +1,148 @@
+// Finds SSDV packets in a stream of bytes, such as the output of a modem or the
+// info fields of AX.25 frames, where packets may be lost, damaged or separated
+// by noise.
+
+use arrayvec::ArrayVec;
+
+use crate::{
+    decoder::{self, Header, SYNC},
+    encoder::{MIN_PACKET_SIZE, PACKET_SIZE},
+    DecodeError,
+};
+
+/// Splits a byte stream into SSDV packets, ready to be fed to a [`Decoder`](crate::Decoder).
+///
+/// Packets start at a sync byte and are only passed on once their CRC checks out,
+/// after repairing them with their FEC bytes if needed. Anything else is skipped
+/// until the next sync byte.
+pub struct Framer {
+    packet_length: usize,
+    buffer: ArrayVec<u8, PACKET_SIZE>,
+}
+
+impl Framer {
+    pub fn new() -> Self {
+        Self {
+            packet_length: PACKET_SIZE,
+            buffer: ArrayVec::new(),
+        }
+    }
+
+    /// Creates a framer for packets of `packet_length` bytes, between 64 and 256
+    pub fn with_packet_length(packet_length: usize) -> Result<Self, DecodeError> {
+        if !(MIN_PACKET_SIZE..=PACKET_SIZE).contains(&packet_length) {
+            return Err(DecodeError::PacketLength);
+        }
+
+        return Ok(Self {
+            packet_length,
+            ..Self::new()
+        });
+    }
+
+    /// Adds the next byte of the stream, returning a packet if it was the last byte of one
+    pub fn push(&mut self, byte: u8) -> Option<ArrayVec<u8, PACKET_SIZE>> {
+        if self.buffer.is_empty() && byte != SYNC {
+            return None;
+        }
+
+        self.buffer.push(byte);
+        if self.buffer.len() < self.packet_length {
+            return None;
+        }
+
+        let mut packet = [0; PACKET_SIZE];
+        packet[..self.packet_length].copy_from_slice(&self.buffer);
+
+        if Header::parse(&packet, self.packet_length).is_ok()
+            || decoder::repair(&mut packet, self.packet_length).is_some()
+        {
+            self.buffer.clear();
+            return Some(packet[..self.packet_length].iter().copied().collect());
+        }
+
+        // Not a packet after all, try again from the next sync byte
+        let next = self.buffer[1..]
+            .iter()
+            .position(|b| *b == SYNC)
+            .map_or(self.buffer.len(), |i| i + 1);
+        self.buffer.drain(..next);
+
+        return None;
+    }
+
+    /// Adds a run of bytes from the stream, returning the packets they complete
+    pub fn push_slice(&mut self, data: &[u8]) -> Vec<ArrayVec<u8, PACKET_SIZE>> {
+        return data.iter().filter_map(|b| self.push(*b)).collect();
+    }
+}
+
+impl Default for Framer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Encoder, PacketType, Quality};
+
+    fn packets(packet_length: usize) -> Vec<Vec<u8>> {
+        Encoder::new(
+            *b"SOMETH",
+            0,
+            Quality::Q3,
+            include_bytes!("../balloon.jpg").to_vec(),
+        )
+        .with_packet_type(PacketType::Normal)
+        .with_packet_length(packet_length)
+        .unwrap()
+        .take(8)
+        .map(|packet| packet.unwrap().to_vec())
+        .collect()
+    }
+
+    #[test]
+    fn finds_packets_among_noise() {
+        let packets = packets(128);
+
+        // Noise with stray sync bytes before, between and after the packets
+        let mut stream = vec![SYNC, 0x12, SYNC, 0x66];
+        for packet in &packets {
+            stream.extend_from_slice(packet);
+            stream.extend_from_slice(&[0x00, SYNC, 0xFF]);
+        }
+
+        let mut framer = Framer::with_packet_length(128).unwrap();
+        let found: Vec<Vec<u8>> = framer
+            .push_slice(&stream)
+            .iter()
+            .map(|p| p.to_vec())
+            .collect();
+
+        assert_eq!(found, packets);
+    }
+
+    #[test]
+    fn repairs_damaged_packets() {
+        let packets = packets(256);
+
+        // The sync byte itself has to survive for the packet to be found
+        let mut damaged = packets[1].clone();
+        damaged[1] = 0x00;
+        damaged[100] ^= 0xFF;
+        damaged[200] ^= 0xFF;
+
+        let mut framer = Framer::new();
+        let found = framer.push_slice(&damaged);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].to_vec(), packets[1]);
+    }
+
+    #[test]
+    fn rejects_invalid_packet_lengths() {
+        assert!(Framer::with_packet_length(63).is_err());
+        assert!(Framer::with_packet_length(257).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use std::io::{self, Write};
+
+use crate::png;
+
+/// An 8-bit RGB image, as rendered by the [`Decoder`](crate::Decoder)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Image {
+    width: u16,
+    height: u16,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    pub(crate) fn new(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize * 3],
+        }
+    }
+
+    pub fn width(&self) -> u16 {
+        return self.width;
+    }
+
+    pub fn height(&self) -> u16 {
+        return self.height;
+    }
+
+    /// The raw pixel data, three bytes (R, G, B) per pixel in row order
+    pub fn pixels(&self) -> &[u8] {
+        return &self.pixels;
+    }
+
+    pub fn pixel(&self, x: u16, y: u16) -> [u8; 3] {
+        let i = self.index(x, y);
+        return [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2]];
+    }
+
+    /// Writes the image as a binary (P6) PPM
+    pub fn write_ppm<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+        writer.write_all(&self.pixels)?;
+
+        return Ok(());
+    }
+
+    /// Writes the image as an 8-bit RGB PNG
+    pub fn write_png<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        return png::write(writer, self.width, self.height, &self.pixels);
+    }
+
+    pub(crate) fn set_pixel(&mut self, x: u16, y: u16, rgb: [u8; 3]) {
+        let i = self.index(x, y);
+        self.pixels[i..i + 3].copy_from_slice(&rgb);
+    }
+
+    fn index(&self, x: u16, y: u16) -> usize {
+        return (y as usize * self.width as usize + x as usize) * 3;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_pixels() {
+        let mut image = Image::new(3, 2);
+        image.set_pixel(2, 1, [10, 20, 30]);
+
+        assert_eq!(image.pixel(2, 1), [10, 20, 30]);
+        assert_eq!(image.pixel(1, 1), [0, 0, 0]);
+        assert_eq!(image.pixels()[15..], [10, 20, 30]);
+    }
+
+    #[test]
+    fn writes_ppm() {
+        let mut image = Image::new(2, 1);
+        image.set_pixel(1, 0, [1, 2, 3]);
+
+        let mut out = Vec::new();
+        image.write_ppm(&mut out).unwrap();
+        assert_eq!(out, b"P6\n2 1\n255\n\0\0\0\x01\x02\x03");
+    }
+}
@@ -0,0 +1,673 @@
+// Support for baseline JPEGs that don't code the image as a single interleaved
+// scan, such as those with one scan per component. The encoder works through the
+// image data as it arrives, one MCU at a time, so these are decoded into buffered
+// coefficients and then coded again as one interleaved scan it can follow. The
+// same goes for images with sampling factors SSDV has no MCU mode for, which
+// have their components resampled along the way. Resampling isn't done on the
+// coefficients themselves: a component whose size changes is decoded to samples,
+// resized and coded again, as the sizes don't always differ by a power of two
+// (4:1:1 chroma goes from a quarter of the width to a half).
+
+use crate::{
+    encoder::{EncodeError, STD_DHT00, STD_DHT01, STD_DHT10, STD_DHT11},
+    jpeg::{self, ZIGZAG},
+};
+
+/// A component taking part in a scan
+pub(crate) struct ScanComponent<'a> {
+    /// Index of the component in the frame header
+    pub index: usize,
+    /// The DC DHT table, including the leading class and id byte
+    pub dc: &'a [u8],
+    /// The AC DHT table, including the leading class and id byte
+    pub ac: &'a [u8],
+}
+
+/// Quantised coefficients for each block of a component, in zigzag order
+struct Component {
+    /// Horizontal and vertical sampling factors
+    sampling: (usize, usize),
+    /// Width and height in samples, not counting the padding out to whole MCUs
+    size: (usize, usize),
+    blocks_wide: usize,
+    blocks: Vec<[i16; 64]>,
+}
+
+impl Component {
+    fn new(sampling: (usize, usize), size: (usize, usize), mcus: (usize, usize)) -> Self {
+        let blocks_wide = mcus.0 * sampling.0;
+        let blocks_high = mcus.1 * sampling.1;
+
+        Self {
+            sampling,
+            size,
+            blocks_wide,
+            blocks: vec![[0; 64]; blocks_wide * blocks_high],
+        }
+    }
+
+    /// Decodes the component's samples, in row order
+    fn samples(&self, dqt: &[u16]) -> Vec<f32> {
+        let (width, height) = self.size;
+        let mut samples = vec![0.0; width * height];
+
+        for by in 0..height.div_ceil(8) {
+            for bx in 0..width.div_ceil(8) {
+                let mut block = [0.0; 64];
+                for (i, c) in self.blocks[by * self.blocks_wide + bx].iter().enumerate() {
+                    block[ZIGZAG[i]] = *c as f64 * dqt.get(i).copied().unwrap_or(1) as f64;
+                }
+
+                let block = jpeg::idct(&block);
+
+                for y in 0..8.min(height - by * 8) {
+                    for x in 0..8.min(width - bx * 8) {
+                        samples[(by * 8 + y) * width + bx * 8 + x] = block[y * 8 + x] as f32;
+                    }
+                }
+            }
+        }
+
+        return samples;
+    }
+
+    /// Codes samples into the component's blocks. The size must be a multiple of 8.
+    fn set_samples(&mut self, samples: &[f32], dqt: &[u16]) {
+        let (width, height) = self.size;
+
+        for by in 0..height / 8 {
+            for bx in 0..width / 8 {
+                let mut block = [0.0; 64];
+                for y in 0..8 {
+                    for x in 0..8 {
+                        block[y * 8 + x] = samples[(by * 8 + y) * width + bx * 8 + x] as f64;
+                    }
+                }
+
+                let block = jpeg::fdct(&block);
+
+                let coefficients = &mut self.blocks[by * self.blocks_wide + bx];
+                for (i, c) in coefficients.iter_mut().enumerate() {
+                    let q = dqt.get(i).copied().unwrap_or(1).max(1) as f64;
+                    let min = if i == 0 { -1024.0 } else { -1023.0 };
+                    *c = (block[ZIGZAG[i]] / q).round().clamp(min, 1023.0) as i16;
+                }
+            }
+        }
+    }
+}
+
+pub(crate) struct Interleaver {
+    components: Vec<Component>,
+    width: usize,
+    height: usize,
+    mcus_wide: usize,
+    mcus_high: usize,
+}
+
+impl Interleaver {
+    /// Creates an interleaver for components with the sampling factors from the frame header,
+    /// horizontal in the high nibble and vertical in the low nibble
+    pub(crate) fn new(sampling: &[u8], width: u16, height: u16) -> Self {
+        let (width, height) = (width as usize, height as usize);
+
+        let sampling: Vec<(usize, usize)> = sampling
+            .iter()
+            .map(|s| ((s >> 4) as usize, (s & 0x0F) as usize))
+            .collect();
+        let h_max = sampling.iter().map(|s| s.0).max().unwrap_or(1);
+        let v_max = sampling.iter().map(|s| s.1).max().unwrap_or(1);
+
+        let mcus_wide = width.div_ceil(8 * h_max);
+        let mcus_high = height.div_ceil(8 * v_max);
+
+        let components = sampling
+            .iter()
+            .map(|s| {
+                let size = (
+                    (width * s.0).div_ceil(h_max),
+                    (height * s.1).div_ceil(v_max),
+                );
+                Component::new(*s, size, (mcus_wide, mcus_high))
+            })
+            .collect();
+
+        Self {
+            components,
+            width,
+            height,
+            mcus_wide,
+            mcus_high,
+        }
+    }
+
+    /// Resamples the components to the sampling factors of an SSDV MCU mode, where the first
+    /// (Y) component is at the full resolution of the image. `dqt` holds the quantisation
+    /// table for each component. Components that stay the same size keep their coefficients,
+    /// the rest go through the IDCT, [`resize`] and the FDCT.
+    pub(crate) fn resample(&mut self, mcu_mode: u8, dqt: &[&[u16]]) {
+        let (h, v) = match mcu_mode {
+            0 => (2, 2),
+            1 => (1, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+
+        self.mcus_wide = self.width / (8 * h);
+        self.mcus_high = self.height / (8 * v);
+
+        for (i, c) in self.components.iter_mut().enumerate() {
+            let (sampling, size) = if i == 0 {
+                ((h, v), (self.width, self.height))
+            } else {
+                ((1, 1), (self.width / h, self.height / v))
+            };
+
+            let mut resampled = Component::new(sampling, size, (self.mcus_wide, self.mcus_high));
+
+            if c.size == size {
+                // Only the arrangement of the blocks changes
+                for by in 0..size.1 / 8 {
+                    for bx in 0..size.0 / 8 {
+                        resampled.blocks[by * resampled.blocks_wide + bx] =
+                            c.blocks[by * c.blocks_wide + bx];
+                    }
+                }
+            } else {
+                let samples = resize(&c.samples(dqt[i]), c.size, size);
+                resampled.set_samples(&samples, dqt[i]);
+            }
+
+            *c = resampled;
+        }
+    }
+
+    /// Decodes the coefficients from a scan's image data, returning how many bytes of
+    /// `data` it took up. `restart_interval` is the DRI value in effect for the scan.
+    pub(crate) fn decode_scan(
+        &mut self,
+        data: &[u8],
+        scan: &[ScanComponent],
+        restart_interval: u16,
+    ) -> Result<usize, EncodeError> {
+        let mut reader = BitReader::new(data);
+        let mut dc = [0i16; 3];
+
+        // The blocks coded in each MCU, as the component's position in the scan and the block
+        // number. A scan with a single component covers its blocks in plain row order.
+        let mcus: Vec<Vec<(usize, usize)>> = if let [component] = scan {
+            let c = &self.components[component.index];
+            let wide = c.size.0.div_ceil(8);
+            let high = c.size.1.div_ceil(8);
+
+            (0..wide * high)
+                .map(|b| vec![(0, (b / wide) * c.blocks_wide + b % wide)])
+                .collect()
+        } else {
+            (0..self.mcus_wide * self.mcus_high)
+                .map(|mcu| {
+                    let (mx, my) = (mcu % self.mcus_wide, mcu / self.mcus_wide);
+                    let mut blocks = Vec::new();
+
+                    for (i, component) in scan.iter().enumerate() {
+                        let c = &self.components[component.index];
+                        for y in 0..c.sampling.1 {
+                            for x in 0..c.sampling.0 {
+                                let bx = mx * c.sampling.0 + x;
+                                let by = my * c.sampling.1 + y;
+                                blocks.push((i, by * c.blocks_wide + bx));
+                            }
+                        }
+                    }
+
+                    blocks
+                })
+                .collect()
+        };
+
+        for (i, blocks) in mcus.iter().enumerate() {
+            if restart_interval > 0 && i > 0 && i.is_multiple_of(restart_interval as usize) {
+                reader.restart();
+                dc.fill(0);
+            }
+
+            for (i, block) in blocks {
+                let component = &scan[*i];
+
+                let coefficients = reader.block(component, &mut dc[component.index])?;
+                self.components[component.index].blocks[*block] = coefficients;
+            }
+        }
+
+        return Ok(reader.pos);
+    }
+
+    /// Codes the buffered coefficients as a single interleaved scan using the standard
+    /// huffman tables, followed by an EOI marker
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let dc_tables = [HuffCodes::new(&STD_DHT00), HuffCodes::new(&STD_DHT01)];
+        let ac_tables = [HuffCodes::new(&STD_DHT10), HuffCodes::new(&STD_DHT11)];
+
+        let mut writer = BitWriter::default();
+        let mut dc = [0i16; 3];
+
+        for my in 0..self.mcus_high {
+            for mx in 0..self.mcus_wide {
+                for (i, c) in self.components.iter().enumerate() {
+                    let tables = if i == 0 { 0 } else { 1 };
+
+                    for y in 0..c.sampling.1 {
+                        for x in 0..c.sampling.0 {
+                            let bx = mx * c.sampling.0 + x;
+                            let by = my * c.sampling.1 + y;
+                            let block = &c.blocks[by * c.blocks_wide + bx];
+
+                            let diff = block[0] as isize - dc[i] as isize;
+                            dc[i] = block[0];
+
+                            writer.block(block, diff, &dc_tables[tables], &ac_tables[tables])?;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut out = writer.finish();
+        out.extend_from_slice(&[0xFF, 0xD9]);
+
+        return Ok(out);
+    }
+
+    /// Sets every block of a component to a flat colour
+    #[cfg(test)]
+    pub(crate) fn fill(&mut self, component: usize, dc: i16) {
+        for block in &mut self.components[component].blocks {
+            *block = [0; 64];
+            block[0] = dc;
+        }
+    }
+}
+
+/// Resizes a plane of samples, averaging the samples each new one covers when shrinking it
+fn resize(samples: &[f32], from: (usize, usize), to: (usize, usize)) -> Vec<f32> {
+    let span = |i: usize, from: usize, to: usize| {
+        let start = i * from / to;
+        start..((i + 1) * from / to).max(start + 1)
+    };
+
+    // Horizontally first, then vertically
+    let mut rows = vec![0.0; to.0 * from.1];
+    for y in 0..from.1 {
+        for x in 0..to.0 {
+            let span = span(x, from.0, to.0);
+            let sum: f32 = span.clone().map(|sx| samples[y * from.0 + sx]).sum();
+            rows[y * to.0 + x] = sum / span.len() as f32;
+        }
+    }
+
+    let mut out = vec![0.0; to.0 * to.1];
+    for y in 0..to.1 {
+        let span = span(y, from.1, to.1);
+        for x in 0..to.0 {
+            let sum: f32 = span.clone().map(|sy| rows[sy * to.0 + x]).sum();
+            out[y * to.0 + x] = sum / span.len() as f32;
+        }
+    }
+
+    return out;
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u8,
+    len: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            bits: 0,
+            len: 0,
+        }
+    }
+
+    fn bit(&mut self) -> Result<u16, EncodeError> {
+        if self.len == 0 {
+            let b = *self.data.get(self.pos).ok_or(EncodeError::OutOfBits)?;
+            self.pos += 1;
+
+            if b == 0xFF {
+                match self.data.get(self.pos) {
+                    // A stuffed zero byte
+                    Some(0x00) => self.pos += 1,
+                    // The scan ended before all of its blocks were coded
+                    Some(_) => return Err(EncodeError::InvalidData),
+                    None => return Err(EncodeError::OutOfBits),
+                }
+            }
+
+            self.bits = b;
+            self.len = 8;
+        }
+
+        self.len -= 1;
+        return Ok((self.bits >> self.len) as u16 & 1);
+    }
+
+    fn bits(&mut self, len: u8) -> Result<u16, EncodeError> {
+        let mut bits = 0;
+        for _ in 0..len {
+            bits = (bits << 1) | self.bit()?;
+        }
+
+        return Ok(bits);
+    }
+
+    /// Drops what's left of the current byte and skips over the RST marker that should follow
+    fn restart(&mut self) {
+        self.len = 0;
+
+        if let Some([0xFF, 0xD0..=0xD7, ..]) = self.data.get(self.pos..) {
+            self.pos += 2;
+        }
+    }
+
+    fn huff(&mut self, table: &[u8]) -> Result<u8, EncodeError> {
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 17;
+
+        for len in 1..=16 {
+            code = (code << 1) | self.bit()? as usize;
+
+            let count = *table.get(len).ok_or(EncodeError::Dht)? as usize;
+            if code < first + count {
+                let symbol = table.get(index + code - first);
+                return symbol.copied().ok_or(EncodeError::NoMatch);
+            }
+
+            index += count;
+            first = (first + count) << 1;
+        }
+
+        return Err(EncodeError::NoMatch);
+    }
+
+    /// Reads a `len` bit wide coefficient value
+    fn value(&mut self, len: u8) -> Result<isize, EncodeError> {
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let bits = self.bits(len)? as isize;
+        if bits < 1 << (len - 1) {
+            return Ok(bits - (1 << len) + 1);
+        } else {
+            return Ok(bits);
+        }
+    }
+
+    fn block(&mut self, component: &ScanComponent, dc: &mut i16) -> Result<[i16; 64], EncodeError> {
+        let mut block = [0; 64];
+
+        let len = self.huff(component.dc)?;
+        if len > 11 {
+            return Err(EncodeError::InvalidData);
+        }
+
+        *dc = dc
+            .checked_add(self.value(len)? as i16)
+            .ok_or(EncodeError::InvalidData)?;
+        block[0] = *dc;
+
+        let mut k = 1;
+        while k < 64 {
+            let symbol = self.huff(component.ac)?;
+            let (run, len) = ((symbol >> 4) as usize, symbol & 0x0F);
+
+            if len == 0 {
+                if run == 15 {
+                    k += 16;
+                    continue;
+                }
+
+                // EOB
+                break;
+            }
+
+            k += run;
+            if k > 63 || len > 10 {
+                return Err(EncodeError::InvalidData);
+            }
+
+            block[k] = self.value(len)? as i16;
+            k += 1;
+        }
+
+        return Ok(block);
+    }
+}
+
+/// The code and its length for each symbol of a huffman table
+struct HuffCodes([(u16, u8); 256]);
+
+impl HuffCodes {
+    fn new(table: &[u8]) -> Self {
+        let mut codes = [(0, 0); 256];
+        let mut symbols = table[17..].iter();
+        let mut code = 0;
+
+        for len in 1..=16u8 {
+            for _ in 0..table[len as usize] {
+                if let Some(symbol) = symbols.next() {
+                    codes[*symbol as usize] = (code, len);
+                }
+                code += 1;
+            }
+
+            code <<= 1;
+        }
+
+        return Self(codes);
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    len: u8,
+}
+
+impl BitWriter {
+    fn bits(&mut self, bits: u16, len: u8) {
+        self.bits = (self.bits << len) | (bits as u32 & ((1 << len) - 1));
+        self.len += len;
+
+        while self.len >= 8 {
+            let b = (self.bits >> (self.len - 8)) as u8;
+            self.out.push(b);
+            if b == 0xFF {
+                self.out.push(0x00);
+            }
+
+            self.len -= 8;
+        }
+    }
+
+    fn symbol(&mut self, codes: &HuffCodes, symbol: u8) -> Result<(), EncodeError> {
+        let (code, len) = codes.0[symbol as usize];
+        if len == 0 {
+            return Err(EncodeError::InvalidData);
+        }
+
+        self.bits(code, len);
+        return Ok(());
+    }
+
+    /// Number of bits needed to code a coefficient value
+    fn value_len(value: isize) -> u8 {
+        return (usize::BITS - value.unsigned_abs().leading_zeros()) as u8;
+    }
+
+    fn value(&mut self, value: isize, len: u8) {
+        let bits = if value < 0 { value - 1 } else { value };
+        self.bits(bits as u16, len);
+    }
+
+    fn block(
+        &mut self,
+        block: &[i16; 64],
+        dc: isize,
+        dc_codes: &HuffCodes,
+        ac_codes: &HuffCodes,
+    ) -> Result<(), EncodeError> {
+        let len = Self::value_len(dc);
+        self.symbol(dc_codes, len)?;
+        self.value(dc, len);
+
+        let mut run = 0;
+        for ac in &block[1..] {
+            if *ac == 0 {
+                run += 1;
+                continue;
+            }
+
+            while run >= 16 {
+                self.symbol(ac_codes, 0xF0)?;
+                run -= 16;
+            }
+
+            let len = Self::value_len(*ac as isize);
+            self.symbol(ac_codes, (run << 4) | len)?;
+            self.value(*ac as isize, len);
+            run = 0;
+        }
+
+        if run > 0 {
+            // EOB
+            self.symbol(ac_codes, 0x00)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Pads the last byte with 1 bits
+    fn finish(mut self) -> Vec<u8> {
+        let pad = (8 - self.len % 8) % 8;
+        self.bits(0xFF, pad);
+
+        return self.out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Components with 2x2 luma sampling, two MCUs wide and one high
+    fn interleaver() -> Interleaver {
+        let mut interleaver = Interleaver::new(&[0x22, 0x11, 0x11], 32, 16);
+        for (i, c) in interleaver.components.iter_mut().enumerate() {
+            for (b, block) in c.blocks.iter_mut().enumerate() {
+                for (k, coefficient) in block.iter_mut().enumerate() {
+                    if (k + b) % (i + 3) == 0 {
+                        *coefficient = (k as i16 * 13 - b as i16 * 7 + i as i16) % 300;
+                    }
+                }
+            }
+        }
+        interleaver
+    }
+
+    fn blocks(interleaver: &Interleaver) -> Vec<Vec<[i16; 64]>> {
+        interleaver
+            .components
+            .iter()
+            .map(|c| c.blocks.clone())
+            .collect()
+    }
+
+    #[test]
+    fn decodes_encoded_scan() {
+        let original = interleaver();
+        let data = original.encode().unwrap();
+        assert_eq!(data[data.len() - 2..], [0xFF, 0xD9]);
+
+        let luma = ScanComponent {
+            index: 0,
+            dc: &STD_DHT00,
+            ac: &STD_DHT10,
+        };
+        let chroma = |index| ScanComponent {
+            index,
+            dc: &STD_DHT01,
+            ac: &STD_DHT11,
+        };
+
+        let mut decoded = Interleaver::new(&[0x22, 0x11, 0x11], 32, 16);
+        let len = decoded
+            .decode_scan(&data, &[luma, chroma(1), chroma(2)], 0)
+            .unwrap();
+
+        assert_eq!(len, data.len() - 2);
+        assert_eq!(blocks(&decoded), blocks(&original));
+    }
+
+    #[test]
+    fn decodes_single_component_scans() {
+        let mut original = Interleaver::new(&[0x11], 24, 16);
+        for (b, block) in original.components[0].blocks.iter_mut().enumerate() {
+            block[0] = b as i16 * 40 - 100;
+            block[b + 1] = 5;
+        }
+        let data = original.encode().unwrap();
+
+        let mut decoded = Interleaver::new(&[0x11], 24, 16);
+        let luma = ScanComponent {
+            index: 0,
+            dc: &STD_DHT00,
+            ac: &STD_DHT10,
+        };
+        decoded.decode_scan(&data, &[luma], 0).unwrap();
+
+        assert_eq!(blocks(&decoded), blocks(&original));
+    }
+
+    #[test]
+    fn resizes_planes() {
+        let flat = vec![50.0; 16 * 8];
+        assert_eq!(resize(&flat, (16, 8), (8, 4)), vec![50.0; 8 * 4]);
+
+        let stripes: Vec<f32> = (0..4 * 2).map(|i| (i % 2) as f32 * 10.0).collect();
+        assert_eq!(resize(&stripes, (4, 2), (2, 1)), vec![5.0, 5.0]);
+        assert_eq!(
+            resize(&[1.0, 2.0], (2, 1), (4, 1)),
+            vec![1.0, 1.0, 2.0, 2.0]
+        );
+    }
+
+    #[test]
+    fn resamples_to_mcu_mode() {
+        // 1x1 sampling on every component, with a flat grey luma plane
+        let mut interleaver = Interleaver::new(&[0x11, 0x11, 0x11], 16, 16);
+        for block in &mut interleaver.components[0].blocks {
+            block[0] = 80;
+        }
+
+        let dqt = [1u16; 64];
+        interleaver.resample(0, &[&dqt, &dqt, &dqt]);
+
+        assert_eq!((interleaver.mcus_wide, interleaver.mcus_high), (1, 1));
+        assert_eq!(interleaver.components[0].sampling, (2, 2));
+        assert_eq!(interleaver.components[1].size, (8, 8));
+        assert!(interleaver.components[0].blocks.iter().all(|b| b[0] == 80));
+        assert!(interleaver.components[1]
+            .blocks
+            .iter()
+            .all(|b| *b == [0; 64]));
+    }
+}
@@ -0,0 +1,142 @@
+// The pixel half of a baseline JPEG decoder. Entropy decoding happens in the
+// decoder itself (it has to follow the SSDV packet boundaries), this takes the
+// quantised coefficients from there and turns them back into pixels. The
+// encoder also uses the DCTs here when it has to resample a component.
+
+/// Maps a coefficient's position in zigzag order to its position in the 8x8 block
+pub(crate) const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// `C(u) * cos((2x + 1) * u * pi / 16) / 2`, indexed by `[x][u]`
+#[rustfmt::skip]
+const IDCT: [[f64; 8]; 8] = [
+    [0.353553391, 0.490392640, 0.461939766, 0.415734806, 0.353553391, 0.277785117, 0.191341716, 0.097545161],
+    [0.353553391, 0.415734806, 0.191341716, -0.097545161, -0.353553391, -0.490392640, -0.461939766, -0.277785117],
+    [0.353553391, 0.277785117, -0.191341716, -0.490392640, -0.353553391, 0.097545161, 0.461939766, 0.415734806],
+    [0.353553391, 0.097545161, -0.461939766, -0.277785117, 0.353553391, 0.415734806, -0.191341716, -0.490392640],
+    [0.353553391, -0.097545161, -0.461939766, 0.277785117, 0.353553391, -0.415734806, -0.191341716, 0.490392640],
+    [0.353553391, -0.277785117, -0.191341716, 0.490392640, -0.353553391, -0.097545161, 0.461939766, -0.415734806],
+    [0.353553391, -0.415734806, 0.191341716, 0.097545161, -0.353553391, 0.490392640, -0.461939766, 0.277785117],
+    [0.353553391, -0.490392640, 0.461939766, -0.415734806, 0.353553391, -0.277785117, 0.191341716, -0.097545161],
+];
+
+/// Dequantises a block of coefficients (in zigzag order) with a DQT table
+/// (including the leading table id byte) and runs the inverse DCT over it,
+/// returning the level shifted 8x8 samples in row order.
+pub(crate) fn decode_block(coefficients: &[i16; 64], dqt: &[u8; 65]) -> [u8; 64] {
+    let mut block = [0f64; 64];
+    for (i, c) in coefficients.iter().enumerate() {
+        block[ZIGZAG[i]] = *c as f64 * dqt[1 + i] as f64;
+    }
+
+    let samples = idct(&block);
+
+    let mut out = [0u8; 64];
+    for (o, s) in out.iter_mut().zip(samples) {
+        *o = (s + 128.0).round().clamp(0.0, 255.0) as u8;
+    }
+
+    return out;
+}
+
+/// Inverse DCT of a block of coefficients in row order
+pub(crate) fn idct(block: &[f64; 64]) -> [f64; 64] {
+    // Rows first, then columns
+    let mut rows = [0f64; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            rows[y * 8 + x] = (0..8).map(|u| IDCT[x][u] * block[y * 8 + u]).sum();
+        }
+    }
+
+    let mut out = [0f64; 64];
+    for x in 0..8 {
+        for y in 0..8 {
+            out[y * 8 + x] = (0..8).map(|v| IDCT[y][v] * rows[v * 8 + x]).sum();
+        }
+    }
+
+    return out;
+}
+
+/// Forward DCT of a block of samples in row order, the inverse of [`idct`]
+pub(crate) fn fdct(samples: &[f64; 64]) -> [f64; 64] {
+    let mut rows = [0f64; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| IDCT[x][u] * samples[y * 8 + x]).sum();
+        }
+    }
+
+    let mut out = [0f64; 64];
+    for u in 0..8 {
+        for v in 0..8 {
+            out[v * 8 + u] = (0..8).map(|y| IDCT[y][v] * rows[y * 8 + u]).sum();
+        }
+    }
+
+    return out;
+}
+
+/// JFIF Y'CbCr to RGB conversion
+pub(crate) fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let y = y as f64;
+    let cb = cb as f64 - 128.0;
+    let cr = cr as f64 - 128.0;
+
+    let r = y + 1.402 * cr;
+    let g = y - 0.344136 * cb - 0.714136 * cr;
+    let b = y + 1.772 * cb;
+
+    return [
+        r.round().clamp(0.0, 255.0) as u8,
+        g.round().clamp(0.0, 255.0) as u8,
+        b.round().clamp(0.0, 255.0) as u8,
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zigzag_covers_block() {
+        let mut seen = [false; 64];
+        for i in ZIGZAG {
+            seen[i] = true;
+        }
+        assert!(seen.iter().all(|s| *s));
+    }
+
+    #[test]
+    fn dct_round_trip() {
+        let samples: [f64; 64] = std::array::from_fn(|i| ((i * 37) % 255) as f64 - 128.0);
+        let restored = idct(&fdct(&samples));
+
+        for (a, b) in samples.iter().zip(restored) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn decodes_flat_blocks() {
+        // A DC coefficient of 8 times the level gives a flat block of that level
+        let mut coefficients = [0; 64];
+        coefficients[0] = 40;
+        let dqt = [1; 65];
+
+        assert_eq!(decode_block(&coefficients, &dqt), [128 + 5; 64]);
+        coefficients[0] = -2000;
+        assert_eq!(decode_block(&coefficients, &dqt), [0; 64]);
+    }
+
+    #[test]
+    fn converts_colours() {
+        assert_eq!(ycbcr_to_rgb(0, 128, 128), [0, 0, 0]);
+        assert_eq!(ycbcr_to_rgb(255, 128, 128), [255, 255, 255]);
+        assert_eq!(ycbcr_to_rgb(76, 85, 255), [254, 0, 0]);
+    }
+}
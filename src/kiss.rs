@@ -0,0 +1,141 @@
+// KISS framing, for passing packets to and from a TNC over a serial link.
+// Frames are delimited by FEND bytes, with any FEND or FESC bytes in the
+// data escaped.
+
+const FEND: u8 = 0xC0;
+const FESC: u8 = 0xDB;
+const TFEND: u8 = 0xDC;
+const TFESC: u8 = 0xDD;
+
+/// The command for a data frame, sent in the low nibble of the frame's first byte
+const DATA_FRAME: u8 = 0x00;
+
+/// A KISS data frame, to or from one of the TNC's ports
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KissFrame {
+    /// The TNC port, 0-15
+    pub port: u8,
+    pub data: Vec<u8>,
+}
+
+impl KissFrame {
+    pub fn new(port: u8, data: &[u8]) -> Self {
+        Self {
+            port: port & 0x0F,
+            data: data.to_vec(),
+        }
+    }
+
+    /// Encodes the frame to send to the TNC, with the FEND and FESC bytes in the data escaped
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(self.data.len() + 4);
+        frame.push(FEND);
+        frame.push((self.port << 4) | DATA_FRAME);
+
+        for b in &self.data {
+            match *b {
+                FEND => frame.extend_from_slice(&[FESC, TFEND]),
+                FESC => frame.extend_from_slice(&[FESC, TFESC]),
+                b => frame.push(b),
+            }
+        }
+
+        frame.push(FEND);
+        return frame;
+    }
+}
+
+/// Splits the byte stream from a TNC into KISS data frames. Frames with other
+/// commands, such as TNC settings, are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct KissDecoder {
+    frame: Vec<u8>,
+    escape: bool,
+}
+
+impl KissDecoder {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Adds the next byte from the TNC, returning a frame if it was the end of one
+    pub fn push(&mut self, byte: u8) -> Option<KissFrame> {
+        match byte {
+            FEND => {
+                self.escape = false;
+
+                let frame = std::mem::take(&mut self.frame);
+                let (&command, data) = frame.split_first()?;
+                if command & 0x0F != DATA_FRAME {
+                    return None;
+                }
+
+                return Some(KissFrame {
+                    port: command >> 4,
+                    data: data.to_vec(),
+                });
+            }
+            FESC => self.escape = true,
+            b if self.escape => {
+                self.escape = false;
+
+                match b {
+                    TFEND => self.frame.push(FEND),
+                    TFESC => self.frame.push(FESC),
+                    // Not a valid escape, pass it through as is
+                    b => self.frame.push(b),
+                }
+            }
+            b => self.frame.push(b),
+        }
+
+        return None;
+    }
+
+    /// Adds a run of bytes from the TNC, returning the frames they complete
+    pub fn push_slice(&mut self, data: &[u8]) -> Vec<KissFrame> {
+        return data.iter().filter_map(|b| self.push(*b)).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_special_bytes() {
+        let frame = KissFrame::new(2, &[0x01, FEND, 0x02, FESC, 0x03]);
+        assert_eq!(
+            frame.encode(),
+            [FEND, 0x20, 0x01, FESC, TFEND, 0x02, FESC, TFESC, 0x03, FEND]
+        );
+    }
+
+    #[test]
+    fn decodes_encoded_frames() {
+        let frames = [
+            KissFrame::new(0, &[FEND, FESC, TFEND, TFESC]),
+            KissFrame::new(15, &(0..=255).collect::<Vec<u8>>()),
+        ];
+
+        let mut stream = vec![0x55, 0xAA];
+        for frame in &frames {
+            stream.extend(frame.encode());
+        }
+
+        let mut decoder = KissDecoder::new();
+        assert_eq!(decoder.push_slice(&stream), frames);
+    }
+
+    #[test]
+    fn skips_other_commands() {
+        // A TXDELAY setting, then back to back data frames sharing a FEND
+        let stream = [FEND, 0x01, 0x32, FEND, 0x00, 0xAB, FEND, 0x10, 0xCD, FEND];
+
+        let mut decoder = KissDecoder::new();
+        assert_eq!(
+            decoder.push_slice(&stream),
+            [KissFrame::new(0, &[0xAB]), KissFrame::new(1, &[0xCD])]
+        );
+    }
+}
@@ -1,8 +1,52 @@
+#![allow(clippy::needless_return)]
+
+mod ax25;
+#[cfg(feature = "ccsds")]
+mod ccsds;
+mod channel;
+#[cfg(feature = "tokio")]
+mod codec;
 mod decoder;
 mod encoder;
+mod framer;
+mod image;
+mod interleave;
+mod jpeg;
+mod kiss;
+mod modem;
+mod multi;
+mod png;
+mod receiver;
+mod relay;
+mod rs;
+#[cfg(feature = "futures")]
+mod stream;
+mod text;
+#[cfg(feature = "upload")]
+mod upload;
+mod wav;
 
-pub use decoder::Decoder;
-pub use encoder::Encoder;
+pub use ax25::{Ax25Address, Ax25Error, UiFrame};
+#[cfg(feature = "ccsds")]
+pub use ccsds::{CcsdsError, Packetizer, SpacePacket, TmChannel, TmDeframer, TmFramer};
+pub use channel::{simulate, Arrival, Channel, LinkReport};
+#[cfg(feature = "tokio")]
+pub use codec::SsdvCodec;
+pub use decoder::{DecodeError, Decoder, ReceivedPacket};
+pub use encoder::{EncodeError, Encoder};
+pub use framer::Framer;
+pub use image::Image;
+pub use kiss::{KissDecoder, KissFrame};
+pub use modem::{Demodulator, ModemConfig, ModemError, Modulation, Modulator};
+pub use multi::{ImageKey, MultiDecoder};
+pub use receiver::ReceiverMetadata;
+pub use relay::{RelaySender, RelayServer};
+#[cfg(feature = "futures")]
+pub use stream::EncoderStream;
+pub use text::{parse_text_line, TextFormat};
+#[cfg(feature = "upload")]
+pub use upload::{upload_json, HttpUploader, UploadRecord, Uploader};
+pub use wav::{read_wav, write_wav};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u16)]
@@ -10,68 +54,32 @@ pub(crate) enum JpegMarker {
     Invalid = 0x0000,
     Tem = 0xFF01,
     Sof0 = 0xFFC0,
-    Sof1,
-    Sof2,
-    Sof3,
-    Dht,
-    Sof5,
-    Sof6,
-    Sof7,
-    Jpg,
-    Sof9,
-    Sof10,
-    Sof11,
-    Dac,
-    Sof13,
-    Sof14,
-    Sof15,
-    Rst0,
-    Rst1,
-    Rst2,
-    Rst3,
-    Rst4,
-    Rst5,
-    Rst6,
-    Rst7,
-    Soi,
-    Eoi,
-    Sos,
-    Dqt,
-    Dnl,
-    Dri,
-    Dhp,
-    Exp,
-    App0,
-    App1,
-    App2,
-    App3,
-    App4,
-    App5,
-    App6,
-    App7,
-    App8,
-    App9,
-    App10,
-    App11,
-    App12,
-    App13,
-    App14,
-    App15,
-    Jpg0,
-    Jpg1,
-    Jpg2,
-    Jpg3,
-    Jpg4,
-    Jpg5,
-    Jpg6,
-    Sof48,
-    Lse,
-    Jpg9,
-    Jpg10,
-    Jpg11,
-    Jpg12,
-    Jpg13,
-    Com,
+    Sof1 = 0xFFC1,
+    Sof2 = 0xFFC2,
+    Sof3 = 0xFFC3,
+    Dht = 0xFFC4,
+    Sof5 = 0xFFC5,
+    Sof6 = 0xFFC6,
+    Sof7 = 0xFFC7,
+    Sof9 = 0xFFC9,
+    Sof10 = 0xFFCA,
+    Sof11 = 0xFFCB,
+    Sof13 = 0xFFCD,
+    Sof14 = 0xFFCE,
+    Sof15 = 0xFFCF,
+    Rst0 = 0xFFD0,
+    Rst1 = 0xFFD1,
+    Rst2 = 0xFFD2,
+    Rst3 = 0xFFD3,
+    Rst4 = 0xFFD4,
+    Rst5 = 0xFFD5,
+    Rst6 = 0xFFD6,
+    Rst7 = 0xFFD7,
+    Eoi = 0xFFD9,
+    Sos = 0xFFDA,
+    Dqt = 0xFFDB,
+    Dri = 0xFFDD,
+    Com = 0xFFFE,
 }
 
 impl PartialEq<u16> for JpegMarker {
@@ -99,24 +107,82 @@ impl PartialOrd<JpegMarker> for u16 {
 }
 
 impl From<u16> for JpegMarker {
+    /// Markers the encoder has no use for, including the APPn and other markers it skips,
+    /// all become `Invalid`
     fn from(value: u16) -> Self {
-        if value != JpegMarker::Tem && (value < JpegMarker::Sof0 || value > JpegMarker::Com) {
-            return JpegMarker::Invalid;
-        }
+        use JpegMarker as J;
 
-        return unsafe { std::mem::transmute(value) };
+        return match value {
+            0xFF01 => J::Tem,
+            0xFFC0 => J::Sof0,
+            0xFFC1 => J::Sof1,
+            0xFFC2 => J::Sof2,
+            0xFFC3 => J::Sof3,
+            0xFFC4 => J::Dht,
+            0xFFC5 => J::Sof5,
+            0xFFC6 => J::Sof6,
+            0xFFC7 => J::Sof7,
+            0xFFC9 => J::Sof9,
+            0xFFCA => J::Sof10,
+            0xFFCB => J::Sof11,
+            0xFFCD => J::Sof13,
+            0xFFCE => J::Sof14,
+            0xFFCF => J::Sof15,
+            0xFFD0 => J::Rst0,
+            0xFFD1 => J::Rst1,
+            0xFFD2 => J::Rst2,
+            0xFFD3 => J::Rst3,
+            0xFFD4 => J::Rst4,
+            0xFFD5 => J::Rst5,
+            0xFFD6 => J::Rst6,
+            0xFFD7 => J::Rst7,
+            0xFFD9 => J::Eoi,
+            0xFFDA => J::Sos,
+            0xFFDB => J::Dqt,
+            0xFFDD => J::Dri,
+            0xFFFE => J::Com,
+            _ => J::Invalid,
+        };
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub(crate) enum PacketType {
-    /// Normal mode (224 byte packet + 32 byte FEC)
+pub enum PacketType {
+    /// Normal mode, the last 32 bytes of each packet are Reed-Solomon FEC
     Normal,
-    /// No-FEC mode (256 byte packet)
+    /// No-FEC mode, the whole packet is header, image data and CRC
     NoFEC,
+    #[allow(dead_code)]
     Padding,
 }
 
+impl PacketType {
+    pub(crate) fn from_byte(byte: u8) -> Option<PacketType> {
+        match byte {
+            0x66 => Some(PacketType::Normal),
+            0x67 => Some(PacketType::NoFEC),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            PacketType::Normal => 0x66,
+            PacketType::NoFEC | PacketType::Padding => 0x67,
+        }
+    }
+
+    /// Number of image data bytes carried by a `packet_length` byte packet of this type
+    pub(crate) fn payload_size(&self, packet_length: usize) -> usize {
+        let payload = packet_length - encoder::HEADER_SIZE - encoder::CRC_SIZE;
+
+        match self {
+            PacketType::Normal => payload - encoder::FEC_SIZE,
+            PacketType::NoFEC | PacketType::Padding => payload,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(u8)]
 pub enum Quality {
@@ -142,6 +208,20 @@ impl Quality {
     pub fn num(&self) -> u8 {
         unsafe { std::mem::transmute_copy(self) }
     }
+
+    pub fn from_num(num: u8) -> Option<Quality> {
+        match num {
+            0 => Some(Quality::Q0),
+            1 => Some(Quality::Q1),
+            2 => Some(Quality::Q2),
+            3 => Some(Quality::Q3),
+            4 => Some(Quality::Q4),
+            5 => Some(Quality::Q5),
+            6 => Some(Quality::Q6),
+            7 => Some(Quality::Q7),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,554 @@
+// Audio modems, for sending packets through a radio's audio input as 8-bit RTTY,
+// as fldigi and dl-fldigi receive it, and getting them back out of its audio
+// output. Tones are phase continuous, and symbol timing is kept to the sample
+// clock so bauds that don't divide the sample rate don't drift.
+//
+// The demodulator measures each tone's energy over the last symbol's worth of
+// samples, recovers the symbol timing from where the strongest tone changes,
+// and frames bytes by their start and stop bits.
+//
+// fldigi's MFSK modes aren't supported. Being understood by fldigi takes its
+// IZ8BLY varicode, convolutional code and interleaver exactly, and a scheme of
+// our own would only be readable by this crate.
+
+use std::{
+    f64::consts::{PI, TAU},
+    time::Duration,
+};
+
+/// Peak level of the generated audio, leaving headroom for the transmitter's audio chain
+const AMPLITUDE: f64 = 0.5 * i16::MAX as f64;
+
+/// RTTY's low tone, sent for start bits and 0 bits
+const SPACE: usize = 0;
+/// RTTY's high tone, sent for stop bits, 1 bits and while idle
+const MARK: usize = 1;
+
+/// RTTY's two tones
+const TONES: usize = 2;
+
+/// Changes of tone the timing recovery averages over
+const TIMING_TRANSITIONS: f64 = 32.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Modulation {
+    /// Two tone RTTY, with a start bit, 8 data bits sent LSB first, no parity and 1 or 2
+    /// stop bits (8N1 or 8N2)
+    Rtty { stop_bits: u8 },
+}
+
+/// Tones and timing shared by the modulator and demodulator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModemConfig {
+    pub(crate) modulation: Modulation,
+    pub(crate) baud: f64,
+    pub(crate) shift: f64,
+    pub(crate) frequency: f64,
+    pub(crate) sample_rate: u32,
+}
+
+impl ModemConfig {
+    /// Settings for `modulation` at 300 baud, with tones 600 Hz apart centred on 1500 Hz,
+    /// sampled at 48 kHz
+    pub fn new(modulation: Modulation) -> Self {
+        Self {
+            modulation,
+            baud: 300.0,
+            shift: 600.0,
+            frequency: 1500.0,
+            sample_rate: 48000,
+        }
+    }
+
+    /// Sets the symbol rate, in symbols per second
+    pub fn with_baud(mut self, baud: f64) -> Self {
+        self.baud = baud;
+        return self;
+    }
+
+    /// Sets the spacing between tones in Hz, the mark/space shift for RTTY
+    pub fn with_shift(mut self, shift: f64) -> Self {
+        self.shift = shift;
+        return self;
+    }
+
+    /// Sets the audio frequency in Hz the tones are centred on
+    pub fn with_frequency(mut self, frequency: f64) -> Self {
+        self.frequency = frequency;
+        return self;
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = sample_rate;
+        return self;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        return self.sample_rate;
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ModemError> {
+        let Modulation::Rtty { stop_bits } = self.modulation;
+        if !(1..=2).contains(&stop_bits) {
+            return Err(ModemError::StopBits);
+        }
+
+        // At least 4 samples per symbol
+        let sample_rate = self.sample_rate as f64;
+        if !self.baud.is_finite() || self.baud <= 0.0 || self.baud * 4.0 > sample_rate {
+            return Err(ModemError::Baud);
+        }
+
+        if !self.shift.is_finite() || self.shift <= 0.0 {
+            return Err(ModemError::Shift);
+        }
+
+        let lowest = self.tone(SPACE);
+        let highest = self.tone(MARK);
+        if !lowest.is_finite() || lowest <= 0.0 || highest >= sample_rate / 2.0 {
+            return Err(ModemError::Frequency);
+        }
+
+        return Ok(());
+    }
+
+    /// Frequency of the tone for a symbol, lowest first
+    pub(crate) fn tone(&self, symbol: usize) -> f64 {
+        return self.frequency + self.shift * (symbol as f64 - (TONES as f64 - 1.0) / 2.0);
+    }
+
+    /// Number of the sample that symbol number `symbol` starts at
+    pub(crate) fn symbol_start(&self, symbol: u64) -> u64 {
+        return (symbol as f64 * self.sample_rate as f64 / self.baud).round() as u64;
+    }
+}
+
+/// Turns bytes into audio samples
+#[derive(Debug, Clone)]
+pub struct Modulator {
+    config: ModemConfig,
+    phase: f64,
+    /// Symbols and samples generated so far
+    symbols: u64,
+    samples: u64,
+}
+
+impl Modulator {
+    pub fn new(config: ModemConfig) -> Result<Self, ModemError> {
+        config.validate()?;
+
+        return Ok(Self {
+            config,
+            phase: 0.0,
+            symbols: 0,
+            samples: 0,
+        });
+    }
+
+    /// Modulates a run of bytes, such as a packet, returning its audio
+    pub fn modulate(&mut self, data: &[u8]) -> Vec<i16> {
+        let mut samples = Vec::new();
+
+        let Modulation::Rtty { stop_bits } = self.config.modulation;
+        for b in data {
+            self.symbol(SPACE, &mut samples);
+            for i in 0..8 {
+                self.symbol((b >> i) as usize & 1, &mut samples);
+            }
+            for _ in 0..stop_bits {
+                self.symbol(MARK, &mut samples);
+            }
+        }
+
+        return samples;
+    }
+
+    /// Audio for sending nothing for at least `duration`, to give the receiver something to
+    /// lock on to before the first packet. RTTY idles on its mark tone.
+    pub fn idle(&mut self, duration: Duration) -> Vec<i16> {
+        let mut samples = Vec::new();
+        let symbols = (duration.as_secs_f64() * self.config.baud).ceil() as u64;
+
+        for _ in 0..symbols {
+            self.symbol(MARK, &mut samples);
+        }
+
+        return samples;
+    }
+
+    fn symbol(&mut self, symbol: usize, samples: &mut Vec<i16>) {
+        let step = TAU * self.config.tone(symbol) / self.config.sample_rate as f64;
+
+        self.symbols += 1;
+        let end = self.config.symbol_start(self.symbols);
+
+        while self.samples < end {
+            samples.push((self.phase.sin() * AMPLITUDE) as i16);
+            self.phase = (self.phase + step) % TAU;
+            self.samples += 1;
+        }
+    }
+}
+
+/// Measures the energy of one tone over a sliding window of samples
+#[derive(Debug, Clone)]
+struct ToneDetector {
+    step: f64,
+    phase: f64,
+    /// The samples in the window, mixed down by the tone
+    window: Vec<(f64, f64)>,
+    sum: (f64, f64),
+}
+
+impl ToneDetector {
+    fn new(frequency: f64, sample_rate: u32, window: usize) -> Self {
+        Self {
+            step: TAU * frequency / sample_rate as f64,
+            phase: 0.0,
+            window: vec![(0.0, 0.0); window],
+            sum: (0.0, 0.0),
+        }
+    }
+
+    /// Adds a sample, replacing the one at `position` in the window
+    fn push(&mut self, sample: f64, position: usize) {
+        let mixed = (sample * self.phase.cos(), -sample * self.phase.sin());
+        let old = std::mem::replace(&mut self.window[position], mixed);
+
+        self.sum.0 += mixed.0 - old.0;
+        self.sum.1 += mixed.1 - old.1;
+        self.phase = (self.phase + self.step) % TAU;
+    }
+
+    fn energy(&self) -> f64 {
+        return self.sum.0 * self.sum.0 + self.sum.1 * self.sum.1;
+    }
+}
+
+/// Turns audio samples back into bytes, ready to be passed to a [`Framer`](crate::Framer).
+/// The tones are expected to be within a small fraction of the baud of where the config
+/// puts them.
+#[derive(Debug, Clone)]
+pub struct Demodulator {
+    detectors: Vec<ToneDetector>,
+    /// Length of a symbol in samples
+    period: f64,
+    window: usize,
+    /// Average of where through a symbol the strongest tone changes, as a unit vector, so
+    /// the average wraps around from one symbol to the next
+    timing: (f64, f64),
+    /// The strongest tone at the last sample
+    tone: Option<usize>,
+    /// Sample to read the next symbol at
+    next: f64,
+    /// Samples received so far
+    samples: u64,
+    /// Mark has been heard since the last byte, so space is a start bit
+    mark: bool,
+    /// Bits read so far of the byte being received, counting the start bit, and its value
+    byte: Option<(u8, u8)>,
+}
+
+impl Demodulator {
+    pub fn new(config: ModemConfig) -> Result<Self, ModemError> {
+        config.validate()?;
+
+        let period = config.sample_rate as f64 / config.baud;
+        let window = period.round() as usize;
+
+        let detectors = (0..TONES)
+            .map(|symbol| ToneDetector::new(config.tone(symbol), config.sample_rate, window))
+            .collect();
+
+        return Ok(Self {
+            detectors,
+            period,
+            window,
+            timing: (0.0, 0.0),
+            tone: None,
+            next: 0.0,
+            samples: 0,
+            mark: false,
+            byte: None,
+        });
+    }
+
+    /// Adds a run of audio samples, returning the bytes they complete
+    pub fn push(&mut self, samples: &[i16]) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        for sample in samples {
+            let position = (self.samples % self.window as u64) as usize;
+            for detector in &mut self.detectors {
+                detector.push(*sample as f64, position);
+            }
+
+            self.samples += 1;
+
+            // Wait for the window to fill
+            if self.samples < self.window as u64 {
+                continue;
+            }
+
+            if let Some(symbol) = self.symbol() {
+                self.rtty(symbol, &mut data);
+            }
+        }
+
+        return data;
+    }
+
+    /// Keeps track of the symbol timing, returning the strongest tone when it's time to
+    /// read a symbol
+    fn symbol(&mut self) -> Option<usize> {
+        let symbol = self
+            .detectors
+            .iter()
+            .map(ToneDetector::energy)
+            .enumerate()
+            .fold(
+                (0, f64::MIN),
+                |best, (i, e)| if e > best.1 { (i, e) } else { best },
+            )
+            .0;
+
+        let now = self.samples as f64;
+
+        // The strongest tone changes when the window is half way across a symbol boundary
+        if self.tone.is_some_and(|tone| tone != symbol) {
+            let phase = TAU * (now - self.window as f64 / 2.0) / self.period;
+            self.timing.0 += (phase.cos() - self.timing.0) / TIMING_TRANSITIONS;
+            self.timing.1 += (phase.sin() - self.timing.1) / TIMING_TRANSITIONS;
+        }
+        self.tone = Some(symbol);
+
+        if now < self.next {
+            return None;
+        }
+
+        // Steer the next read towards the window lining up with a symbol, going whichever
+        // way round is shorter
+        let boundary = self.timing.1.atan2(self.timing.0);
+        let error = (boundary - TAU * now / self.period + PI).rem_euclid(TAU) - PI;
+        self.next = now + self.period * (1.0 + error / TAU);
+
+        return Some(symbol);
+    }
+
+    fn rtty(&mut self, symbol: usize, data: &mut Vec<u8>) {
+        let Some((bits, value)) = &mut self.byte else {
+            if symbol == MARK {
+                self.mark = true;
+            } else if self.mark {
+                self.mark = false;
+                self.byte = Some((1, 0));
+            }
+
+            return;
+        };
+
+        match bits {
+            1..=8 => *value |= (symbol as u8) << (*bits - 1),
+            // Only the first stop bit is checked, any more just look like idle
+            _ => {
+                if symbol == MARK {
+                    data.push(*value);
+                }
+
+                self.mark = symbol == MARK;
+                self.byte = None;
+                return;
+            }
+        }
+
+        *bits += 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModemError {
+    /// RTTY must have 1 or 2 stop bits
+    StopBits,
+    /// The baud must be positive, with at least 4 samples per symbol
+    Baud,
+    /// The shift between tones must be positive
+    Shift,
+    /// All the tones must be between 0 Hz and half the sample rate
+    Frequency,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read_wav, write_wav, Encoder, Framer, Quality};
+
+    /// Energy of `frequency` in a run of samples
+    fn energy(samples: &[i16], frequency: f64, sample_rate: u32) -> f64 {
+        let step = TAU * frequency / sample_rate as f64;
+        let (re, im) = samples
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(re, im), (i, s)| {
+                let phase = step * i as f64;
+                (re + *s as f64 * phase.cos(), im - *s as f64 * phase.sin())
+            });
+
+        return re * re + im * im;
+    }
+
+    /// The stronger tone in each symbol of `samples`
+    fn symbols(config: &ModemConfig, samples: &[i16]) -> Vec<usize> {
+        let mut symbols = Vec::new();
+
+        for i in 0.. {
+            let start = config.symbol_start(i) as usize;
+            let end = config.symbol_start(i + 1) as usize;
+            if end > samples.len() {
+                break;
+            }
+
+            let space = energy(&samples[start..end], config.tone(SPACE), config.sample_rate);
+            let mark = energy(&samples[start..end], config.tone(MARK), config.sample_rate);
+            symbols.push(if mark > space { MARK } else { SPACE });
+        }
+
+        return symbols;
+    }
+
+    #[test]
+    fn validates_config() {
+        let rtty = ModemConfig::new(Modulation::Rtty { stop_bits: 1 });
+        assert!(Modulator::new(rtty).is_ok());
+
+        let invalid = [
+            (
+                ModemConfig::new(Modulation::Rtty { stop_bits: 0 }),
+                ModemError::StopBits,
+            ),
+            (
+                ModemConfig::new(Modulation::Rtty { stop_bits: 3 }),
+                ModemError::StopBits,
+            ),
+            (rtty.with_baud(0.0), ModemError::Baud),
+            (rtty.with_baud(20000.0), ModemError::Baud),
+            (rtty.with_shift(-100.0), ModemError::Shift),
+            (rtty.with_frequency(200.0), ModemError::Frequency),
+            (rtty.with_sample_rate(3000), ModemError::Frequency),
+        ];
+
+        for (config, err) in invalid {
+            assert_eq!(Modulator::new(config).unwrap_err(), err);
+            assert_eq!(Demodulator::new(config).unwrap_err(), err);
+        }
+    }
+
+    #[test]
+    fn places_tones_around_frequency() {
+        let config = ModemConfig::new(Modulation::Rtty { stop_bits: 1 });
+        assert_eq!((config.tone(SPACE), config.tone(MARK)), (1200.0, 1800.0));
+
+        let config = config.with_frequency(1000.0).with_shift(170.0);
+        assert_eq!((config.tone(SPACE), config.tone(MARK)), (915.0, 1085.0));
+    }
+
+    #[test]
+    fn modulates_rtty() {
+        let config = ModemConfig::new(Modulation::Rtty { stop_bits: 2 });
+        let mut modulator = Modulator::new(config).unwrap();
+        let samples = modulator.modulate(&[0x55, 0x0f]);
+
+        // 11 symbols per byte of 160 samples each
+        assert_eq!(samples.len(), 2 * 11 * 160);
+        assert_eq!(
+            symbols(&config, &samples),
+            [
+                0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 1, // 0x55
+                0, 1, 1, 1, 1, 0, 0, 0, 0, 1, 1, // 0x0f, LSB first
+            ]
+        );
+
+        let config = ModemConfig::new(Modulation::Rtty { stop_bits: 1 });
+        let samples = Modulator::new(config).unwrap().modulate(&[0x80]);
+        assert_eq!(symbols(&config, &samples), [0, 0, 0, 0, 0, 0, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn idles_on_mark() {
+        let config = ModemConfig::new(Modulation::Rtty { stop_bits: 1 });
+        let samples = Modulator::new(config)
+            .unwrap()
+            .idle(Duration::from_millis(100));
+
+        assert_eq!(symbols(&config, &samples), [MARK; 30]);
+    }
+
+    #[test]
+    fn keeps_phase_and_timing_continuous() {
+        // 45.45 baud doesn't divide 48 kHz, so symbols alternate between lengths
+        let config = ModemConfig::new(Modulation::Rtty { stop_bits: 2 })
+            .with_baud(45.45)
+            .with_shift(170.0);
+        let mut modulator = Modulator::new(config).unwrap();
+
+        let mut samples = modulator.idle(Duration::from_millis(500));
+        for b in 0..=255 {
+            samples.extend(modulator.modulate(&[b]));
+        }
+
+        let symbols = (0.5 * 45.45f64).ceil() as u64 + 256 * 11;
+        assert_eq!(samples.len() as u64, config.symbol_start(symbols));
+
+        // Neither tone can move a sample further than this without the phase jumping
+        let step = TAU * config.tone(MARK) / config.sample_rate as f64;
+        let limit = (AMPLITUDE * step).ceil() as i32 + 1;
+        assert!(samples
+            .windows(2)
+            .all(|w| (w[1] as i32 - w[0] as i32).abs() <= limit));
+        assert!(samples.iter().all(|s| (*s as f64).abs() <= AMPLITUDE));
+    }
+
+    #[test]
+    fn round_trips_through_wav() {
+        let packets: Vec<Vec<u8>> = Encoder::new(
+            *b"SOMETH",
+            0,
+            Quality::Q3,
+            include_bytes!("../balloon.jpg").to_vec(),
+        )
+        .take(3)
+        .map(|packet| packet.unwrap().to_vec())
+        .collect();
+
+        for stop_bits in [1, 2] {
+            let config = ModemConfig::new(Modulation::Rtty { stop_bits });
+
+            let mut modulator = Modulator::new(config).unwrap();
+            let mut samples = modulator.idle(Duration::from_millis(500));
+            for packet in &packets {
+                samples.extend(modulator.modulate(packet));
+            }
+            samples.extend(modulator.idle(Duration::from_millis(500)));
+
+            let mut wav = Vec::new();
+            write_wav(&mut wav, config.sample_rate(), &samples).unwrap();
+            let (sample_rate, samples) = read_wav(&mut wav.as_slice()).unwrap();
+            assert_eq!(sample_rate, config.sample_rate());
+
+            let mut demodulator = Demodulator::new(config).unwrap();
+            let data: Vec<u8> = samples
+                .chunks(1000)
+                .flat_map(|chunk| demodulator.push(chunk))
+                .collect();
+
+            // Idling on mark doesn't start any bytes
+            assert_eq!(data, packets.concat());
+
+            let framed: Vec<Vec<u8>> = Framer::new()
+                .push_slice(&data)
+                .iter()
+                .map(|packet| packet.to_vec())
+                .collect();
+            assert_eq!(framed, packets);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+
+use log::info;
+
+use crate::{
+    decoder::{self, Header},
+    encoder::{MIN_PACKET_SIZE, PACKET_SIZE},
+    DecodeError, Decoder, ReceivedPacket,
+};
+
+/// Images kept by default before the least recently updated ones are dropped
+const DEFAULT_MAX_IMAGES: usize = 16;
+
+/// Identifies an image by the callsign that sent it, its image id and its size. The
+/// size tells apart images sent with the same id, such as after the ids wrap around.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ImageKey {
+    pub callsign: String,
+    pub image_id: u8,
+    pub width: u16,
+    pub height: u16,
+}
+
+struct ImageDecoder {
+    decoder: Decoder,
+    /// When the image last had a packet fed to it, as a count of packets fed to any image
+    last_fed: u64,
+}
+
+/// Decodes packets from any number of images at once, such as from several payloads
+/// on one frequency, with a [`Decoder`] for each image.
+///
+/// Only the most recently updated images are kept, the least recently updated one is
+/// dropped to make room for a new image once the limit is reached.
+pub struct MultiDecoder {
+    packet_length: usize,
+    max_images: usize,
+    images: BTreeMap<ImageKey, ImageDecoder>,
+    /// Packets fed so far
+    fed: u64,
+}
+
+impl MultiDecoder {
+    pub fn new() -> Self {
+        Self {
+            packet_length: PACKET_SIZE,
+            max_images: DEFAULT_MAX_IMAGES,
+            images: BTreeMap::new(),
+            fed: 0,
+        }
+    }
+
+    /// Creates a decoder for packets of `packet_length` bytes, between 64 and 256
+    pub fn with_packet_length(packet_length: usize) -> Result<Self, DecodeError> {
+        if !(MIN_PACKET_SIZE..=PACKET_SIZE).contains(&packet_length) {
+            return Err(DecodeError::PacketLength);
+        }
+
+        return Ok(Self {
+            packet_length,
+            ..Self::new()
+        });
+    }
+
+    /// Sets how many images are kept at once, 16 by default. At least one image is always kept.
+    pub fn with_max_images(mut self, max_images: usize) -> Self {
+        self.max_images = max_images.max(1);
+        self
+    }
+
+    /// Feeds a packet to the decoder for its image, returning which image that is.
+    ///
+    /// A packet with the same key as an image but different settings, such as its quality,
+    /// is rejected with [`DecodeError::Image`].
+    pub fn feed<'a, P: Into<ReceivedPacket<'a>>>(
+        &mut self,
+        packet: P,
+    ) -> Result<ImageKey, DecodeError> {
+        let packet = packet.into();
+
+        if packet.data.len() < self.packet_length {
+            return Err(DecodeError::Length);
+        }
+
+        let mut data = [0; PACKET_SIZE];
+        data[..self.packet_length].copy_from_slice(&packet.data[..self.packet_length]);
+
+        // The key comes from the header, which might need repairing first
+        let header = match Header::parse(&data, self.packet_length) {
+            Ok(header) => header,
+            Err(err @ (DecodeError::Sync | DecodeError::PacketType | DecodeError::Crc)) => {
+                decoder::repair(&mut data, self.packet_length).ok_or(err)?
+            }
+            Err(err) => return Err(err),
+        };
+
+        let key = ImageKey {
+            callsign: header.callsign(),
+            image_id: header.image_id,
+            width: header.width,
+            height: header.height,
+        };
+
+        // Already repaired if it needed it, so the decoder only has to check it
+        let received = ReceivedPacket {
+            data: &data[..self.packet_length],
+            metadata: packet.metadata,
+        };
+        let fed = self.fed + 1;
+
+        if let Some(image) = self.images.get_mut(&key) {
+            image.decoder.feed(received)?;
+            image.last_fed = fed;
+        } else {
+            // Another image is only dropped once the new one's first packet has been accepted
+            let mut decoder = Decoder::with_packet_length(self.packet_length)?;
+            decoder.feed(received)?;
+
+            info!(
+                "New image {} from {}, {}x{}",
+                key.image_id, key.callsign, key.width, key.height
+            );
+
+            self.make_room();
+            self.images.insert(
+                key.clone(),
+                ImageDecoder {
+                    decoder,
+                    last_fed: fed,
+                },
+            );
+        }
+
+        self.fed = fed;
+        return Ok(key);
+    }
+
+    pub fn get(&self, key: &ImageKey) -> Option<&Decoder> {
+        return self.images.get(key).map(|image| &image.decoder);
+    }
+
+    /// Stops decoding an image, handing back its decoder
+    pub fn remove(&mut self, key: &ImageKey) -> Option<Decoder> {
+        return self.images.remove(key).map(|image| image.decoder);
+    }
+
+    /// Every image still being decoded
+    pub fn images(&self) -> impl Iterator<Item = (&ImageKey, &Decoder)> {
+        return self.images.iter().map(|(key, image)| (key, &image.decoder));
+    }
+
+    /// Drops the least recently updated images until there's room for another
+    fn make_room(&mut self) {
+        while self.images.len() >= self.max_images {
+            let Some(oldest) = self
+                .images
+                .iter()
+                .min_by_key(|(_, image)| image.last_fed)
+                .map(|(key, _)| key.clone())
+            else {
+                return;
+            };
+
+            info!(
+                "Dropping image {} from {}",
+                oldest.image_id, oldest.callsign
+            );
+            self.images.remove(&oldest);
+        }
+    }
+}
+
+impl Default for MultiDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Encoder, PacketType, Quality};
+
+    fn encode(callsign: &[u8; 6], image_id: u8, image: &[u8]) -> Vec<Vec<u8>> {
+        Encoder::new(*callsign, image_id, Quality::Q3, image.to_vec())
+            .with_packet_type(PacketType::Normal)
+            .map(|packet| packet.unwrap().to_vec())
+            .collect()
+    }
+
+    fn balloon(callsign: &[u8; 6], image_id: u8) -> Vec<Vec<u8>> {
+        encode(callsign, image_id, include_bytes!("../balloon.jpg"))
+    }
+
+    #[test]
+    fn decodes_interleaved_images() {
+        let a = balloon(b"AAAAAA", 0);
+        let b = balloon(b"BBBBBB", 0);
+
+        let mut decoder = MultiDecoder::new();
+        for (a, b) in a.iter().zip(&b) {
+            decoder.feed(&a[..]).unwrap();
+            decoder.feed(&b[..]).unwrap();
+        }
+
+        let keys: Vec<&ImageKey> = decoder.images().map(|(key, _)| key).collect();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].callsign, "AAAAAA");
+        assert_eq!(keys[1].callsign, "BBBBBB");
+        assert!(decoder.images().all(|(_, d)| d.is_complete()));
+    }
+
+    #[test]
+    fn keeps_images_with_the_same_id_apart() {
+        let balloon = balloon(b"SOMETH", 7);
+        let kitty = Encoder::new(
+            *b"SOMETH",
+            7,
+            Quality::Q3,
+            include_bytes!("../kitty.jpeg").to_vec(),
+        )
+        .next()
+        .unwrap()
+        .unwrap();
+
+        let mut decoder = MultiDecoder::new();
+        let first = decoder.feed(&balloon[0][..]).unwrap();
+        let second = decoder.feed(&kitty[..]).unwrap();
+        assert_ne!(first, second);
+
+        // A packet that clashes with the image under its key is rejected, leaving the image be
+        let other_quality = Encoder::new(
+            *b"SOMETH",
+            7,
+            Quality::Q5,
+            include_bytes!("../balloon.jpg").to_vec(),
+        )
+        .next()
+        .unwrap()
+        .unwrap();
+        assert_eq!(decoder.feed(&other_quality[..]), Err(DecodeError::Image));
+
+        // Feeding more of the first image carries on with it rather than starting again
+        decoder.feed(&balloon[1][..]).unwrap();
+        assert_eq!(decoder.get(&first).unwrap().receptions().count(), 2);
+        assert_eq!(decoder.images().count(), 2);
+    }
+
+    #[test]
+    fn drops_least_recently_updated_images() {
+        let mut decoder = MultiDecoder::new().with_max_images(2);
+
+        let keys: Vec<ImageKey> = (0..3)
+            .map(|id| balloon(b"SOMETH", id))
+            .map(|packets| decoder.feed(&packets[0][..]).unwrap())
+            .collect();
+        assert!(decoder.get(&keys[0]).is_none());
+        assert!(decoder.get(&keys[1]).is_some());
+
+        // Updating image 1 makes image 2 the oldest
+        decoder.feed(&balloon(b"SOMETH", 1)[1][..]).unwrap();
+        decoder.feed(&balloon(b"SOMETH", 0)[0][..]).unwrap();
+        assert!(decoder.get(&keys[1]).is_some());
+        assert!(decoder.get(&keys[2]).is_none());
+    }
+
+    #[test]
+    fn repairs_packets_before_decoding() {
+        let mut packets = balloon(b"SOMETH", 0);
+        for packet in &mut packets {
+            packet[0] = 0;
+            packet[50] ^= 0xFF;
+        }
+
+        let mut decoder = MultiDecoder::new();
+        for packet in &packets {
+            decoder.feed(&packet[..]).unwrap();
+        }
+
+        assert!(decoder.images().all(|(_, d)| d.is_complete()));
+    }
+
+    #[test]
+    fn only_drops_images_for_accepted_packets() {
+        let mut decoder = MultiDecoder::new().with_max_images(1);
+        let key = decoder.feed(&balloon(b"SOMETH", 0)[0][..]).unwrap();
+
+        // Too damaged to repair
+        let mut damaged = balloon(b"SOMETH", 1).remove(0);
+        for b in &mut damaged[..40] {
+            *b ^= 0x5A;
+        }
+
+        assert!(decoder.feed(&damaged[..]).is_err());
+        assert!(decoder.get(&key).is_some());
+    }
+}
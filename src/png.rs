@@ -0,0 +1,377 @@
+// Just enough of PNG and deflate to write out 8-bit RGB images. Compression
+// uses the fixed huffman codes with a simple hash based LZ77 match finder,
+// which gets most of the way there for decoded SSDV images without needing
+// a dependency on a full zlib implementation.
+
+use std::io::{self, Write};
+
+use crate::encoder::crc32;
+
+const SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// Sub filter, each byte is stored as the difference from the same channel of the pixel to its left
+const FILTER_SUB: u8 = 1;
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Writes 8-bit RGB pixel data as a PNG
+pub(crate) fn write<W: Write>(
+    writer: &mut W,
+    width: u16,
+    height: u16,
+    rgb: &[u8],
+) -> io::Result<()> {
+    writer.write_all(&SIGNATURE)?;
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    ihdr.push(8); // Bit depth
+    ihdr.push(2); // Colour type (RGB)
+    ihdr.push(0); // Compression method
+    ihdr.push(0); // Filter method
+    ihdr.push(0); // Interlace method
+    write_chunk(writer, b"IHDR", &ihdr)?;
+
+    let stride = width as usize * 3;
+    let mut filtered = Vec::with_capacity((stride + 1) * height as usize);
+    for row in rgb.chunks(stride) {
+        filtered.push(FILTER_SUB);
+        for (i, b) in row.iter().enumerate() {
+            let left = if i >= 3 { row[i - 3] } else { 0 };
+            filtered.push(b.wrapping_sub(left));
+        }
+    }
+
+    write_chunk(writer, b"IDAT", &zlib(&filtered))?;
+    write_chunk(writer, b"IEND", &[])?;
+
+    return Ok(());
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+
+    let mut crc_data = Vec::with_capacity(data.len() + 4);
+    crc_data.extend_from_slice(kind);
+    crc_data.extend_from_slice(data);
+
+    writer.write_all(&crc_data)?;
+    writer.write_all(&crc32(&crc_data).to_be_bytes())?;
+
+    return Ok(());
+}
+
+/// Wraps a deflate stream in a zlib header and adler32 trailer
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    return out;
+}
+
+/// Compresses `data` as a single deflate block using the fixed huffman codes
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::default();
+
+    out.bits(1, 1); // Final block
+    out.bits(0b01, 2); // Fixed huffman codes
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best = (0, 0);
+
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(&data[i..i + MIN_MATCH]);
+            let mut candidate = head[h];
+            let mut chain = 0;
+
+            while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && chain < 32 {
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[i..])
+                    .take(MAX_MATCH)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+
+                if len > best.0 {
+                    best = (len, i - candidate);
+                }
+
+                if len == MAX_MATCH {
+                    break;
+                }
+
+                candidate = prev[candidate];
+                chain += 1;
+            }
+
+            prev[i] = head[h];
+            head[h] = i;
+        }
+
+        if best.0 >= MIN_MATCH {
+            out.length(best.0);
+            out.distance(best.1);
+
+            // Keep the hash chains up to date for the bytes covered by the match
+            for j in i + 1..(i + best.0).min(data.len().saturating_sub(MIN_MATCH - 1)) {
+                let h = hash(&data[j..j + MIN_MATCH]);
+                prev[j] = head[h];
+                head[h] = j;
+            }
+
+            i += best.0;
+        } else {
+            out.literal(data[i] as u16);
+            i += 1;
+        }
+    }
+
+    out.literal(256); // End of block
+    return out.finish();
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let x = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32;
+    return (x.wrapping_mul(0x9E3779B1) >> (32 - HASH_BITS)) as usize;
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+
+    return (b << 16) | a;
+}
+
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    len: u8,
+}
+
+impl BitWriter {
+    /// Appends `len` bits, least significant first
+    fn bits(&mut self, bits: u32, len: u8) {
+        self.bits |= bits << self.len;
+        self.len += len;
+
+        while self.len >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Appends a huffman code, which is packed most significant bit first
+    fn code(&mut self, code: u16, len: u8) {
+        let reversed = code.reverse_bits() >> (16 - len);
+        self.bits(reversed as u32, len);
+    }
+
+    fn literal(&mut self, symbol: u16) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn length(&mut self, length: usize) {
+        let i = LENGTH_BASE
+            .iter()
+            .rposition(|b| *b as usize <= length)
+            .unwrap();
+        self.literal(257 + i as u16);
+        self.bits((length - LENGTH_BASE[i] as usize) as u32, LENGTH_EXTRA[i]);
+    }
+
+    fn distance(&mut self, distance: usize) {
+        let i = DIST_BASE
+            .iter()
+            .rposition(|b| *b as usize <= distance)
+            .unwrap();
+        self.code(i as u16, 5);
+        self.bits((distance - DIST_BASE[i] as usize) as u32, DIST_EXTRA[i]);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.out.push(self.bits as u8);
+        }
+
+        return self.out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits least significant first, as deflate packs them
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, len: u8) -> u32 {
+            let mut value = 0;
+            for i in 0..len {
+                let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+                value |= (bit as u32) << i;
+                self.pos += 1;
+            }
+            value
+        }
+
+        /// Reads a huffman code, which is packed most significant bit first
+        fn code_bit(&mut self, code: u16) -> u16 {
+            (code << 1) | self.bits(1) as u16
+        }
+
+        fn literal(&mut self) -> u16 {
+            let mut code = 0;
+            for _ in 0..7 {
+                code = self.code_bit(code);
+            }
+            if code <= 0x17 {
+                return 256 + code;
+            }
+
+            code = self.code_bit(code);
+            match code {
+                0x30..=0xBF => code - 0x30,
+                0xC0..=0xC7 => 280 + code - 0xC0,
+                _ => 144 + self.code_bit(code) - 0x190,
+            }
+        }
+    }
+
+    /// Inflates a single deflate block compressed with the fixed huffman codes
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut reader = BitReader { data, pos: 0 };
+        assert_eq!(reader.bits(1), 1);
+        assert_eq!(reader.bits(2), 0b01);
+
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let symbol = reader.literal();
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => return out,
+                _ => {
+                    let i = symbol as usize - 257;
+                    let length = LENGTH_BASE[i] as usize + reader.bits(LENGTH_EXTRA[i]) as usize;
+
+                    let mut code = 0;
+                    for _ in 0..5 {
+                        code = reader.code_bit(code);
+                    }
+                    let i = code as usize;
+                    let distance = DIST_BASE[i] as usize + reader.bits(DIST_EXTRA[i]) as usize;
+
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let mut data = b"SSDV SSDV SSDV, a picture from a balloon".repeat(20);
+        data.extend(
+            (0..=255)
+                .cycle()
+                .take(70000)
+                .map(|b: u32| (b * 31 % 251) as u8),
+        );
+        data.extend(std::iter::repeat_n(0x80, 1000));
+
+        let compressed = deflate(&data);
+        assert!(compressed.len() < data.len());
+        assert_eq!(inflate(&compressed), data);
+    }
+
+    #[test]
+    fn adler32_checksum() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn writes_chunks() {
+        let rgb: Vec<u8> = (0..4 * 3 * 3).map(|b| b * 7).collect();
+        let mut out = Vec::new();
+        write(&mut out, 4, 3, &rgb).unwrap();
+
+        assert_eq!(out[..8], SIGNATURE);
+
+        let mut chunks = Vec::new();
+        let mut pos = 8;
+        while pos < out.len() {
+            let len = u32::from_be_bytes(out[pos..pos + 4].try_into().unwrap()) as usize;
+            let body = &out[pos + 4..pos + 8 + len];
+            let crc = u32::from_be_bytes(out[pos + 8 + len..pos + 12 + len].try_into().unwrap());
+            assert_eq!(crc32(body), crc);
+
+            chunks.push((body[..4].to_vec(), body[4..].to_vec()));
+            pos += 12 + len;
+        }
+
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, [&b"IHDR"[..], b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 4, 0, 0, 0, 3, 8, 2, 0, 0, 0]);
+
+        // Undo the zlib wrapper and the sub filter on each row
+        let idat = &chunks[1].1;
+        let filtered = inflate(&idat[2..idat.len() - 4]);
+        assert_eq!(idat[idat.len() - 4..], adler32(&filtered).to_be_bytes());
+
+        let mut pixels = Vec::new();
+        for row in filtered.chunks(4 * 3 + 1) {
+            assert_eq!(row[0], FILTER_SUB);
+            let start = pixels.len();
+            for (i, b) in row[1..].iter().enumerate() {
+                let left = if i >= 3 { pixels[start + i - 3] } else { 0 };
+                pixels.push(b.wrapping_add(left));
+            }
+        }
+        assert_eq!(pixels, rgb);
+    }
+}
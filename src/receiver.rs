@@ -0,0 +1,159 @@
+use std::{
+    io::{self, Read, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Information about how a packet was received, as reported by the receiving station
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReceiverMetadata {
+    /// When the packet was received
+    pub timestamp: Option<SystemTime>,
+    /// Callsign or other identifier of the receiving station
+    pub station: Option<String>,
+    /// Receive frequency in Hz
+    pub frequency: Option<u64>,
+    /// Signal to noise ratio in dB
+    pub snr: Option<f32>,
+}
+
+impl ReceiverMetadata {
+    const TIMESTAMP: u8 = 1 << 0;
+    const STATION: u8 = 1 << 1;
+    const FREQUENCY: u8 = 1 << 2;
+    const SNR: u8 = 1 << 3;
+
+    /// Writes the metadata in the binary form used by saved decoder state
+    pub(crate) fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut flags = 0;
+        if self.timestamp.is_some() {
+            flags |= Self::TIMESTAMP;
+        }
+        if self.station.is_some() {
+            flags |= Self::STATION;
+        }
+        if self.frequency.is_some() {
+            flags |= Self::FREQUENCY;
+        }
+        if self.snr.is_some() {
+            flags |= Self::SNR;
+        }
+
+        writer.write_all(&[flags])?;
+
+        if let Some(timestamp) = self.timestamp {
+            let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+            writer.write_all(&since_epoch.as_secs().to_be_bytes())?;
+            writer.write_all(&since_epoch.subsec_nanos().to_be_bytes())?;
+        }
+
+        if let Some(station) = &self.station {
+            // Long names are cut short, without splitting a character
+            let mut len = station.len().min(u8::MAX as usize);
+            while !station.is_char_boundary(len) {
+                len -= 1;
+            }
+
+            let bytes = &station.as_bytes()[..len];
+            writer.write_all(&[bytes.len() as u8])?;
+            writer.write_all(bytes)?;
+        }
+
+        if let Some(frequency) = self.frequency {
+            writer.write_all(&frequency.to_be_bytes())?;
+        }
+
+        if let Some(snr) = self.snr {
+            writer.write_all(&snr.to_be_bytes())?;
+        }
+
+        return Ok(());
+    }
+
+    /// Reads metadata written by [`ReceiverMetadata::write`]
+    pub(crate) fn read<R: Read>(reader: &mut R) -> io::Result<ReceiverMetadata> {
+        let mut flags = [0; 1];
+        reader.read_exact(&mut flags)?;
+        let flags = flags[0];
+
+        let mut metadata = ReceiverMetadata::default();
+
+        if flags & Self::TIMESTAMP != 0 {
+            let mut secs = [0; 8];
+            reader.read_exact(&mut secs)?;
+            let mut nanos = [0; 4];
+            reader.read_exact(&mut nanos)?;
+
+            metadata.timestamp = Some(
+                UNIX_EPOCH + Duration::new(u64::from_be_bytes(secs), u32::from_be_bytes(nanos)),
+            );
+        }
+
+        if flags & Self::STATION != 0 {
+            let mut len = [0; 1];
+            reader.read_exact(&mut len)?;
+
+            let mut station = vec![0; len[0] as usize];
+            reader.read_exact(&mut station)?;
+            metadata.station = Some(String::from_utf8_lossy(&station).into_owned());
+        }
+
+        if flags & Self::FREQUENCY != 0 {
+            let mut frequency = [0; 8];
+            reader.read_exact(&mut frequency)?;
+            metadata.frequency = Some(u64::from_be_bytes(frequency));
+        }
+
+        if flags & Self::SNR != 0 {
+            let mut snr = [0; 4];
+            reader.read_exact(&mut snr)?;
+            metadata.snr = Some(f32::from_be_bytes(snr));
+        }
+
+        return Ok(metadata);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(metadata: &ReceiverMetadata) -> ReceiverMetadata {
+        let mut data = Vec::new();
+        metadata.write(&mut data).unwrap();
+
+        let mut reader = &data[..];
+        let read = ReceiverMetadata::read(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        read
+    }
+
+    #[test]
+    fn writes_and_reads_metadata() {
+        let metadata = ReceiverMetadata {
+            timestamp: Some(UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789)),
+            station: Some("M0XYZ".to_string()),
+            frequency: Some(434_250_000),
+            snr: Some(12.5),
+        };
+        assert_eq!(round_trip(&metadata), metadata);
+
+        let empty = ReceiverMetadata::default();
+        assert_eq!(round_trip(&empty), empty);
+    }
+
+    #[test]
+    fn truncates_long_station_names() {
+        let metadata = ReceiverMetadata {
+            station: Some("A".repeat(300)),
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&metadata).station, Some("A".repeat(255)));
+
+        // 254 bytes of ASCII followed by a two byte character, which mustn't be split
+        let metadata = ReceiverMetadata {
+            station: Some(format!("{}é", "A".repeat(254))),
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&metadata).station, Some("A".repeat(254)));
+    }
+}
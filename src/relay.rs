@@ -0,0 +1,222 @@
+// Relaying packets from receivers to a decoding server over UDP. Each datagram
+// holds one packet that has already passed its CRC check, along with the
+// metadata of the receiver that heard it:
+//
+//   "SSDR" | version | receiver metadata | packet
+
+use std::{
+    io::{self, Cursor},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+use crate::{
+    decoder::{self, Header},
+    encoder::{MIN_PACKET_SIZE, PACKET_SIZE},
+    ReceiverMetadata,
+};
+
+const MAGIC: [u8; 4] = *b"SSDR";
+const VERSION: u8 = 1;
+
+/// Sends packets from a receiver to a [`RelayServer`]
+pub struct RelaySender {
+    socket: UdpSocket,
+    packet_length: usize,
+}
+
+impl RelaySender {
+    /// Creates a sender for `packet_length` byte packets, to the server at `server`
+    pub fn connect<A: ToSocketAddrs>(server: A, packet_length: usize) -> io::Result<Self> {
+        if !(MIN_PACKET_SIZE..=PACKET_SIZE).contains(&packet_length) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet length must be between 64 and 256 bytes",
+            ));
+        }
+
+        let server = server
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no server address"))?;
+
+        let local: SocketAddr = if server.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0; 16], 0).into()
+        };
+
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)?;
+
+        return Ok(Self {
+            socket,
+            packet_length,
+        });
+    }
+
+    /// Sends a packet, after checking its CRC and repairing it if needed. Packets that
+    /// can't be repaired aren't sent.
+    pub fn send(&self, packet: &[u8], metadata: &ReceiverMetadata) -> io::Result<()> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid packet");
+
+        if packet.len() < self.packet_length {
+            return Err(invalid());
+        }
+
+        let mut data = [0; PACKET_SIZE];
+        data[..self.packet_length].copy_from_slice(&packet[..self.packet_length]);
+
+        if Header::parse(&data, self.packet_length).is_err()
+            && decoder::repair(&mut data, self.packet_length).is_none()
+        {
+            return Err(invalid());
+        }
+
+        let mut datagram = Vec::with_capacity(PACKET_SIZE + 64);
+        datagram.extend_from_slice(&MAGIC);
+        datagram.push(VERSION);
+        metadata.write(&mut datagram)?;
+        datagram.extend_from_slice(&data[..self.packet_length]);
+
+        self.socket.send(&datagram)?;
+        return Ok(());
+    }
+}
+
+/// Receives packets relayed by [`RelaySender`]s
+pub struct RelayServer {
+    socket: UdpSocket,
+}
+
+impl RelayServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        return Ok(Self {
+            socket: UdpSocket::bind(addr)?,
+        });
+    }
+
+    /// The address the server is listening on
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        return self.socket.local_addr();
+    }
+
+    /// Sets how long [`RelayServer::recv`] waits for a datagram before giving up with a
+    /// `WouldBlock` or `TimedOut` error, forever if `None`
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        return self.socket.set_read_timeout(timeout);
+    }
+
+    /// Waits for the next datagram, returning its packet, the receiver's metadata and
+    /// where it came from. Datagrams that aren't from a relay are an `InvalidData` error.
+    pub fn recv(&self) -> io::Result<(Vec<u8>, ReceiverMetadata, SocketAddr)> {
+        let mut datagram = [0; 1024];
+        let (len, from) = self.socket.recv_from(&mut datagram)?;
+
+        let datagram = &datagram[..len];
+        if len < MAGIC.len() + 1 || datagram[..4] != MAGIC || datagram[4] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("not a relayed packet from {from}"),
+            ));
+        }
+
+        let mut reader = Cursor::new(&datagram[5..]);
+        let metadata = ReceiverMetadata::read(&mut reader)?;
+        let packet = datagram[5 + reader.position() as usize..].to_vec();
+
+        return Ok((packet, metadata, from));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::{Decoder, Encoder, MultiDecoder, PacketType, Quality, ReceivedPacket};
+
+    #[test]
+    fn relays_packets_to_server() {
+        let packets: Vec<Vec<u8>> = Encoder::new(
+            *b"SOMETH",
+            0,
+            Quality::Q3,
+            include_bytes!("../balloon.jpg").to_vec(),
+        )
+        .with_packet_type(PacketType::Normal)
+        .map(|packet| packet.unwrap().to_vec())
+        .collect();
+
+        let server = RelayServer::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let sender = RelaySender::connect(server.local_addr().unwrap(), PACKET_SIZE).unwrap();
+
+        let metadata = ReceiverMetadata {
+            timestamp: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            station: Some("M0XYZ".to_string()),
+            ..Default::default()
+        };
+
+        // Damaged packets are repaired before they're sent, and ones past repair aren't sent
+        let mut damaged = packets[0].clone();
+        damaged[10] ^= 0xFF;
+        sender.send(&damaged, &metadata).unwrap();
+        assert!(sender.send(&[0; PACKET_SIZE], &metadata).is_err());
+
+        let mut decoder = MultiDecoder::new();
+        let mut key = None;
+        for (i, packet) in packets.iter().enumerate() {
+            // One at a time, so nothing is lost to a full socket buffer
+            if i > 0 {
+                sender.send(packet, &metadata).unwrap();
+            }
+
+            let (received, received_metadata, _) = server.recv().unwrap();
+            assert_eq!(received, *packet);
+            assert_eq!(received_metadata, metadata);
+
+            key = Some(
+                decoder
+                    .feed(ReceivedPacket::new(&received, received_metadata))
+                    .unwrap(),
+            );
+        }
+
+        let decoder = decoder.get(&key.unwrap()).unwrap();
+        assert!(decoder.is_complete());
+
+        let mut expected = Decoder::new();
+        for packet in &packets {
+            expected.feed(&packet[..]).unwrap();
+        }
+
+        let mut png = Vec::new();
+        decoder.image().unwrap().write_png(&mut png).unwrap();
+        let mut expected_png = Vec::new();
+        expected
+            .image()
+            .unwrap()
+            .write_png(&mut expected_png)
+            .unwrap();
+        assert_eq!(png, expected_png);
+    }
+
+    #[test]
+    fn rejects_other_datagrams() {
+        let server = RelayServer::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .send_to(b"hello", server.local_addr().unwrap())
+            .unwrap();
+
+        let err = server.recv().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
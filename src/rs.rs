@@ -0,0 +1,447 @@
+// The (255,223) Reed-Solomon code protecting Normal mode packets. This is the
+// CCSDS code in the conventional basis (the same parameters as Phil Karn's
+// encode_rs_8/decode_rs_8, which the reference SSDV implementation uses).
+// Packets shorter than 256 bytes use a shortened code, where the missing data
+// bytes are treated as leading zeros.
+
+/// Number of parity bytes
+pub(crate) const NROOTS: usize = 32;
+
+const NN: usize = 255;
+const A0: u8 = NN as u8;
+const GFPOLY: u16 = 0x187;
+const FCR: usize = 112;
+const PRIM: usize = 11;
+const IPRIM: usize = 116;
+
+const ALPHA_TO: [u8; 256] = alpha_to();
+const INDEX_OF: [u8; 256] = index_of();
+const GENPOLY: [u8; NROOTS + 1] = genpoly();
+
+const fn alpha_to() -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut sr: u16 = 1;
+
+    let mut i = 0;
+    while i < NN {
+        table[i] = sr as u8;
+        sr <<= 1;
+        if sr & 0x100 != 0 {
+            sr ^= GFPOLY;
+        }
+        i += 1;
+    }
+
+    return table;
+}
+
+const fn index_of() -> [u8; 256] {
+    let alpha_to = alpha_to();
+    let mut table = [0; 256];
+    table[0] = A0;
+
+    let mut i = 0;
+    while i < NN {
+        table[alpha_to[i] as usize] = i as u8;
+        i += 1;
+    }
+
+    return table;
+}
+
+/// The generator polynomial, in index form
+const fn genpoly() -> [u8; NROOTS + 1] {
+    let alpha_to = alpha_to();
+    let index_of = index_of();
+    let mut poly = [0u8; NROOTS + 1];
+    poly[0] = 1;
+
+    let mut i = 0;
+    let mut root = FCR * PRIM;
+    while i < NROOTS {
+        poly[i + 1] = 1;
+
+        // Multiply by (x + alpha^root)
+        let mut j = i;
+        while j > 0 {
+            if poly[j] != 0 {
+                poly[j] = poly[j - 1] ^ alpha_to[modnn(index_of[poly[j] as usize] as usize + root)];
+            } else {
+                poly[j] = poly[j - 1];
+            }
+            j -= 1;
+        }
+        poly[0] = alpha_to[modnn(index_of[poly[0] as usize] as usize + root)];
+
+        i += 1;
+        root += PRIM;
+    }
+
+    let mut i = 0;
+    while i <= NROOTS {
+        poly[i] = index_of[poly[i] as usize];
+        i += 1;
+    }
+
+    return poly;
+}
+
+const fn modnn(x: usize) -> usize {
+    return x % NN;
+}
+
+/// Calculates the parity bytes for `data`, which can be at most 223 bytes long
+pub(crate) fn encode(data: &[u8]) -> [u8; NROOTS] {
+    let mut parity = [0u8; NROOTS];
+
+    for b in data {
+        let feedback = INDEX_OF[(*b ^ parity[0]) as usize];
+
+        if feedback != A0 {
+            for j in 1..NROOTS {
+                parity[j] ^= ALPHA_TO[modnn(feedback as usize + GENPOLY[NROOTS - j] as usize)];
+            }
+        }
+
+        parity.copy_within(1.., 0);
+        parity[NROOTS - 1] = if feedback != A0 {
+            ALPHA_TO[modnn(feedback as usize + GENPOLY[0] as usize)]
+        } else {
+            0
+        };
+    }
+
+    return parity;
+}
+
+/// Corrects errors in a codeword (the data followed by its parity bytes) in place.
+///
+/// `erasures` are the distinct positions in the codeword of any bytes known to be wrong,
+/// such as ones the receiver missed. Each erasure takes one parity byte to correct rather
+/// than the two an error in an unknown place takes, so up to 32 erasures can be
+/// corrected, or fewer along with some errors.
+///
+/// Returns the number of bytes corrected, counting every erasure, or `None` if there
+/// were too many errors to correct, in which case the codeword is left untouched.
+pub(crate) fn decode(codeword: &mut [u8], erasures: &[usize]) -> Option<usize> {
+    if codeword.len() <= NROOTS || codeword.len() > NN || erasures.len() > NROOTS {
+        return None;
+    }
+
+    if erasures.iter().any(|pos| *pos >= codeword.len()) {
+        return None;
+    }
+
+    let pad = NN - codeword.len();
+
+    // Evaluate the codeword at each root of the generator polynomial
+    let mut s = [codeword[0]; NROOTS];
+    for b in &codeword[1..] {
+        for (i, s) in s.iter_mut().enumerate() {
+            *s = if *s == 0 {
+                *b
+            } else {
+                b ^ ALPHA_TO[modnn(INDEX_OF[*s as usize] as usize + (FCR + i) * PRIM)]
+            };
+        }
+    }
+
+    if s.iter().all(|s| *s == 0) {
+        return Some(0);
+    }
+
+    // Syndromes in index form from here on
+    for s in s.iter_mut() {
+        *s = INDEX_OF[*s as usize];
+    }
+
+    // Start the error locator polynomial off with a root for each erasure
+    let mut lambda = [0u8; NROOTS + 1];
+    lambda[0] = 1;
+
+    for (n, pos) in erasures.iter().enumerate() {
+        let u = modnn(PRIM * (NN - 1 - (pos + pad)));
+        for j in (1..=n + 1).rev() {
+            let tmp = INDEX_OF[lambda[j - 1] as usize];
+            if tmp != A0 {
+                lambda[j] ^= ALPHA_TO[modnn(u + tmp as usize)];
+            }
+        }
+    }
+
+    // Berlekamp-Massey to find the rest of it
+
+    let mut b = [0u8; NROOTS + 1];
+    for i in 0..=NROOTS {
+        b[i] = INDEX_OF[lambda[i] as usize];
+    }
+
+    let no_eras = erasures.len();
+    let mut el = no_eras;
+    for r in no_eras + 1..=NROOTS {
+        let mut discr_r = 0;
+        for i in 0..r {
+            if lambda[i] != 0 && s[r - i - 1] != A0 {
+                discr_r ^=
+                    ALPHA_TO[modnn(INDEX_OF[lambda[i] as usize] as usize + s[r - i - 1] as usize)];
+            }
+        }
+        let discr_r = INDEX_OF[discr_r as usize];
+
+        if discr_r == A0 {
+            b.copy_within(..NROOTS, 1);
+            b[0] = A0;
+            continue;
+        }
+
+        let mut t = [0u8; NROOTS + 1];
+        t[0] = lambda[0];
+        for i in 0..NROOTS {
+            t[i + 1] = if b[i] != A0 {
+                lambda[i + 1] ^ ALPHA_TO[modnn(discr_r as usize + b[i] as usize)]
+            } else {
+                lambda[i + 1]
+            };
+        }
+
+        if 2 * el < r + no_eras {
+            el = r + no_eras - el;
+            for i in 0..=NROOTS {
+                b[i] = if lambda[i] == 0 {
+                    A0
+                } else {
+                    modnn(INDEX_OF[lambda[i] as usize] as usize + NN - discr_r as usize) as u8
+                };
+            }
+        } else {
+            b.copy_within(..NROOTS, 1);
+            b[0] = A0;
+        }
+
+        lambda = t;
+    }
+
+    let mut deg_lambda = 0;
+    for (i, l) in lambda.iter_mut().enumerate() {
+        *l = INDEX_OF[*l as usize];
+        if *l != A0 {
+            deg_lambda = i;
+        }
+    }
+
+    // Chien search for the roots of the error locator polynomial
+    let mut reg = lambda;
+    let mut root = [0usize; NROOTS];
+    let mut loc = [0usize; NROOTS];
+    let mut count = 0;
+
+    let mut k = IPRIM - 1;
+    for i in 1..=NN {
+        let mut q = 1;
+        for j in (1..=deg_lambda).rev() {
+            if reg[j] != A0 {
+                reg[j] = modnn(reg[j] as usize + j) as u8;
+                q ^= ALPHA_TO[reg[j] as usize];
+            }
+        }
+
+        if q == 0 {
+            root[count] = i;
+            loc[count] = k;
+            count += 1;
+
+            if count == deg_lambda {
+                break;
+            }
+        }
+
+        k = modnn(k + IPRIM);
+    }
+
+    if deg_lambda == 0 || count != deg_lambda {
+        return None;
+    }
+
+    // Error evaluator polynomial omega(x) = s(x) * lambda(x) mod x^NROOTS, in index form
+    let deg_omega = deg_lambda - 1;
+    let mut omega = [A0; NROOTS + 1];
+    for i in 0..=deg_omega {
+        let mut tmp = 0;
+        for j in 0..=i {
+            if s[i - j] != A0 && lambda[j] != A0 {
+                tmp ^= ALPHA_TO[modnn(s[i - j] as usize + lambda[j] as usize)];
+            }
+        }
+        omega[i] = INDEX_OF[tmp as usize];
+    }
+
+    // Forney's algorithm for the error values
+    let mut corrections = [(0usize, 0u8); NROOTS];
+    for j in 0..count {
+        let mut num1 = 0;
+        for i in 0..=deg_omega {
+            if omega[i] != A0 {
+                num1 ^= ALPHA_TO[modnn(omega[i] as usize + i * root[j])];
+            }
+        }
+
+        let num2 = ALPHA_TO[modnn(root[j] * (FCR - 1))];
+
+        // lambda[i + 1] for even i is the formal derivative of lambda
+        let mut den = 0;
+        for i in (0..=deg_lambda.min(NROOTS - 1) & !1).step_by(2) {
+            if lambda[i + 1] != A0 {
+                den ^= ALPHA_TO[modnn(lambda[i + 1] as usize + i * root[j])];
+            }
+        }
+
+        // An error in the padding means the decoder has been led astray
+        if loc[j] < pad || den == 0 {
+            return None;
+        }
+
+        let value = if num1 == 0 {
+            0
+        } else {
+            ALPHA_TO[modnn(
+                INDEX_OF[num1 as usize] as usize + INDEX_OF[num2 as usize] as usize + NN
+                    - INDEX_OF[den as usize] as usize,
+            )]
+        };
+
+        corrections[j] = (loc[j] - pad, value);
+    }
+
+    for (i, value) in &corrections[..count] {
+        codeword[*i] ^= value;
+    }
+
+    return Some(count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codeword(len: usize) -> Vec<u8> {
+        let mut codeword: Vec<u8> = (0..len - NROOTS).map(|i| (i * 37 + 11) as u8).collect();
+        let parity = encode(&codeword);
+        codeword.extend_from_slice(&parity);
+        codeword
+    }
+
+    /// Damages `count` bytes spread through the codeword
+    fn damage(codeword: &mut [u8], count: usize) {
+        for i in 0..count {
+            let pos = (i * 97 + 5) % codeword.len();
+            codeword[pos] ^= (i as u8).wrapping_mul(29) | 1;
+        }
+    }
+
+    #[test]
+    fn parity_is_linear() {
+        let a: Vec<u8> = (0..223).map(|i| (i * 7) as u8).collect();
+        let b: Vec<u8> = (0..223).map(|i| (i * 13 + 1) as u8).collect();
+        let sum: Vec<u8> = a.iter().zip(&b).map(|(a, b)| a ^ b).collect();
+
+        let parity: Vec<u8> = encode(&a)
+            .iter()
+            .zip(encode(&b))
+            .map(|(a, b)| a ^ b)
+            .collect();
+        assert_eq!(encode(&sum)[..], parity[..]);
+        assert_eq!(encode(&[0; 223]), [0; NROOTS]);
+    }
+
+    #[test]
+    fn accepts_valid_codewords() {
+        for len in [NROOTS + 1, 63, 128, NN] {
+            let mut codeword = codeword(len);
+            assert_eq!(decode(&mut codeword, &[]), Some(0));
+        }
+    }
+
+    #[test]
+    fn corrects_up_to_16_errors() {
+        for len in [63, 128, NN] {
+            for errors in 1..=NROOTS / 2 {
+                let expected = codeword(len);
+                let mut damaged = expected.clone();
+                damage(&mut damaged, errors);
+
+                assert_eq!(decode(&mut damaged, &[]), Some(errors), "length {len}");
+                assert_eq!(damaged, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn gives_up_on_too_many_errors() {
+        let mut damaged = codeword(NN);
+        damage(&mut damaged, NROOTS / 2 + 1);
+
+        let before = damaged.clone();
+        assert_eq!(decode(&mut damaged, &[]), None);
+        assert_eq!(damaged, before);
+    }
+
+    #[test]
+    fn rejects_invalid_lengths() {
+        assert_eq!(decode(&mut [0; NROOTS], &[]), None);
+        assert_eq!(decode(&mut [0; NN + 1], &[]), None);
+    }
+
+    #[test]
+    fn corrects_up_to_32_erasures() {
+        for len in [63, 128, NN] {
+            let expected = codeword(len);
+
+            for erased in [1, 17, NROOTS] {
+                let erasures: Vec<usize> = (0..erased).map(|i| (i * 5 + 3) % len).collect();
+                let mut damaged = expected.clone();
+                for pos in &erasures {
+                    damaged[*pos] = 0;
+                }
+
+                assert_eq!(
+                    decode(&mut damaged, &erasures),
+                    Some(erased),
+                    "length {len}"
+                );
+                assert_eq!(damaged, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn corrects_errors_and_erasures_together() {
+        // 2 * 8 errors + 16 erasures uses every parity byte
+        let expected = codeword(NN);
+        let mut damaged = expected.clone();
+        damage(&mut damaged, 8);
+
+        let erasures: Vec<usize> = (0..16).map(|i| i * 13 + 3).collect();
+        for pos in &erasures {
+            damaged[*pos] ^= 0xA5;
+        }
+
+        assert!(decode(&mut damaged.clone(), &[]).is_none());
+        assert_eq!(decode(&mut damaged, &erasures), Some(24));
+        assert_eq!(damaged, expected);
+    }
+
+    #[test]
+    fn gives_up_on_too_many_erasures() {
+        let mut damaged = codeword(NN);
+        let erasures: Vec<usize> = (0..NROOTS + 1).collect();
+        for pos in &erasures {
+            damaged[*pos] = !damaged[*pos];
+        }
+
+        let before = damaged.clone();
+        assert_eq!(decode(&mut damaged, &erasures), None);
+        assert_eq!(damaged, before);
+
+        assert_eq!(decode(&mut codeword(63), &[63]), None);
+    }
+}
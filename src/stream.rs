@@ -0,0 +1,164 @@
+// Encoding from an async image source, for async pipelines. The image is read
+// a chunk at a time and handed to the encoder, which picks up where it left
+// off each time it runs out.
+
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use arrayvec::ArrayVec;
+use futures_core::Stream;
+use futures_io::AsyncRead;
+use log::error;
+
+use crate::{
+    encoder::{EncodeError, PACKET_SIZE},
+    Encoder,
+};
+
+const READ_SIZE: usize = 4096;
+
+/// An encoder that can be sent between tasks, made with [`Encoder::new_send`]
+type SendEncoder = Encoder<Box<dyn Iterator<Item = u8> + Send>>;
+
+/// Encodes an image read from an [`AsyncRead`], as a [`Stream`] of packets.
+///
+/// Packets are produced as soon as enough of the image has been read for them. Like
+/// the [`Encoder`] iterator, the stream ends after the first error.
+pub struct EncoderStream<R> {
+    encoder: SendEncoder,
+    reader: R,
+    /// Holds each chunk read from `reader` on its way to the encoder
+    buffer: Box<[u8; READ_SIZE]>,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin> EncoderStream<R> {
+    /// Encodes the image from `reader` with `encoder`'s settings. Any image data the encoder
+    /// was created with comes before the data from `reader`.
+    pub fn new(mut encoder: SendEncoder, reader: R) -> Self {
+        encoder.input_pending = true;
+
+        Self {
+            encoder,
+            reader,
+            buffer: Box::new([0; READ_SIZE]),
+            done: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for EncoderStream<R> {
+    type Item = Result<ArrayVec<u8, PACKET_SIZE>, EncodeError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while !this.done {
+            match this.encoder.encode_packet() {
+                Some(Err(EncodeError::OutOfBits)) if this.encoder.input_pending => {}
+                Some(Ok(packet)) => return Poll::Ready(Some(Ok(packet))),
+                packet => {
+                    this.done = true;
+                    return Poll::Ready(packet);
+                }
+            }
+
+            // The encoder has used everything read so far
+            match ready!(Pin::new(&mut this.reader).poll_read(cx, &mut this.buffer[..])) {
+                Ok(0) => this.encoder.input_pending = false,
+                Ok(len) => this.encoder.push_input(this.buffer[..len].to_vec()),
+                Err(err) => {
+                    error!("Unable to read image: {err}");
+                    this.done = true;
+                    return Poll::Ready(Some(Err(EncodeError::Read)));
+                }
+            }
+        }
+
+        return Poll::Ready(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, task::Waker};
+
+    use super::*;
+    use crate::Quality;
+
+    const BALLOON: &[u8] = include_bytes!("../balloon.jpg");
+
+    /// Hands out the image a little at a time, keeping the stream waiting in between
+    struct SlowReader {
+        data: &'static [u8],
+        ready: bool,
+    }
+
+    impl AsyncRead for SlowReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+
+            let len = buf.len().min(self.data.len()).min(1000);
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Poll::Ready(Ok(len))
+        }
+    }
+
+    fn collect<R: AsyncRead + Unpin>(
+        mut stream: EncoderStream<R>,
+    ) -> Vec<Result<ArrayVec<u8, PACKET_SIZE>, EncodeError>> {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut packets = Vec::new();
+
+        loop {
+            match Pin::new(&mut stream).poll_next(&mut cx) {
+                Poll::Ready(Some(packet)) => packets.push(packet),
+                Poll::Ready(None) => return packets,
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    #[test]
+    fn matches_encoder() {
+        let expected: Vec<_> = Encoder::new(*b"SOMETH", 0, Quality::Q3, BALLOON.to_vec()).collect();
+
+        let reader = SlowReader {
+            data: BALLOON,
+            ready: false,
+        };
+        let encoder = Encoder::new_send(*b"SOMETH", 0, Quality::Q3, Vec::new());
+        assert_eq!(collect(EncoderStream::new(encoder, reader)), expected);
+
+        // Image data given to the encoder comes first
+        let (head, tail) = BALLOON.split_at(5000);
+        let encoder = Encoder::new_send(*b"SOMETH", 0, Quality::Q3, head.to_vec());
+        assert_eq!(collect(EncoderStream::new(encoder, tail)), expected);
+    }
+
+    #[test]
+    fn ends_after_an_error() {
+        let encoder = Encoder::new_send(*b"SOMETH", 0, Quality::Q3, Vec::new());
+        let packets = collect(EncoderStream::new(encoder, &b"not a jpeg"[..]));
+
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].is_err());
+    }
+
+    #[test]
+    fn is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<EncoderStream<&[u8]>>();
+    }
+}
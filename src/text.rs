@@ -0,0 +1,173 @@
+// Packets as lines of text, for links and logs that can only carry text. Each
+// packet is written as one line of hex or base64, and lines are read back by
+// looking for a run of either that decodes to a whole packet, so timestamps and
+// other prefixes added by logging tools are skipped over.
+
+use arrayvec::ArrayVec;
+
+use crate::encoder::PACKET_SIZE;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextFormat {
+    /// Two lowercase hex digits per byte
+    Hex,
+    /// Standard base64, with padding
+    Base64,
+}
+
+impl TextFormat {
+    /// Encodes a packet as a line of text, without the line ending
+    pub fn encode(&self, packet: &[u8]) -> String {
+        match self {
+            TextFormat::Hex => {
+                return packet.iter().map(|b| format!("{b:02x}")).collect();
+            }
+            TextFormat::Base64 => {
+                let mut line = String::with_capacity(packet.len().div_ceil(3) * 4);
+
+                for chunk in packet.chunks(3) {
+                    let mut bytes = [0; 3];
+                    bytes[..chunk.len()].copy_from_slice(chunk);
+                    let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+                    for i in 0..4 {
+                        if i <= chunk.len() {
+                            line.push(BASE64[(bits >> (18 - 6 * i)) as usize & 0x3F] as char);
+                        } else {
+                            line.push('=');
+                        }
+                    }
+                }
+
+                return line;
+            }
+        }
+    }
+}
+
+/// Finds a `packet_length` byte packet in a line of hex or base64 text. Anything around
+/// it, such as a timestamp, is ignored.
+pub fn parse_text_line(line: &str, packet_length: usize) -> Option<ArrayVec<u8, PACKET_SIZE>> {
+    let line = line.as_bytes();
+    let mut start = 0;
+
+    while start < line.len() {
+        // Each run of base64 characters, along with any padding after it
+        let run = line[start..]
+            .iter()
+            .position(|b| !is_base64(*b))
+            .map_or(line.len(), |i| start + i);
+        let end = line[run..]
+            .iter()
+            .position(|b| *b != b'=')
+            .map_or(line.len(), |i| run + i);
+
+        let text = &line[start..run];
+        let packet = if text.len() == packet_length * 2 && text.iter().all(u8::is_ascii_hexdigit) {
+            decode_hex(text)
+        } else {
+            decode_base64(text)
+        };
+
+        if let Some(packet) = packet.filter(|p| p.len() == packet_length) {
+            return Some(packet);
+        }
+
+        start = end.max(run + 1);
+    }
+
+    return None;
+}
+
+fn is_base64(b: u8) -> bool {
+    return b.is_ascii_alphanumeric() || b == b'+' || b == b'/';
+}
+
+fn decode_hex(text: &[u8]) -> Option<ArrayVec<u8, PACKET_SIZE>> {
+    let digit = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+
+    let mut packet = ArrayVec::new();
+    for pair in text.chunks_exact(2) {
+        packet
+            .try_push(digit(pair[0])? << 4 | digit(pair[1])?)
+            .ok()?;
+    }
+
+    return Some(packet);
+}
+
+/// Decodes base64 with its padding removed
+fn decode_base64(text: &[u8]) -> Option<ArrayVec<u8, PACKET_SIZE>> {
+    if text.len() % 4 == 1 {
+        return None;
+    }
+
+    let mut packet = ArrayVec::new();
+    for chunk in text.chunks(4) {
+        let mut bits = 0;
+        for (i, c) in chunk.iter().enumerate() {
+            let value = BASE64.iter().position(|b| b == c)? as u32;
+            bits |= value << (18 - 6 * i);
+        }
+
+        for byte in &bits.to_be_bytes()[1..chunk.len()] {
+            packet.try_push(*byte).ok()?;
+        }
+    }
+
+    return Some(packet);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_hex_and_base64() {
+        assert_eq!(TextFormat::Hex.encode(&[0x55, 0x0F, 0xA0]), "550fa0");
+
+        // The padding cases from RFC 4648
+        assert_eq!(TextFormat::Base64.encode(b"foob"), "Zm9vYg==");
+        assert_eq!(TextFormat::Base64.encode(b"fooba"), "Zm9vYmE=");
+        assert_eq!(TextFormat::Base64.encode(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn round_trips_packets() {
+        for packet_length in [64, 65, 66, 256] {
+            let packet: Vec<u8> = (0..packet_length).map(|i| (i * 7 + 0x55) as u8).collect();
+
+            for format in [TextFormat::Hex, TextFormat::Base64] {
+                let line = format.encode(&packet);
+                let parsed = parse_text_line(&line, packet_length).unwrap();
+                assert_eq!(parsed[..], packet[..], "{format:?} {packet_length}");
+            }
+        }
+    }
+
+    #[test]
+    fn skips_prefixes() {
+        let packet: Vec<u8> = (0..64).collect();
+
+        for format in [TextFormat::Hex, TextFormat::Base64] {
+            let line = format!("2024-06-01 12:00:00 rx: {}  \r", format.encode(&packet));
+            let parsed = parse_text_line(&line, 64).unwrap();
+            assert_eq!(parsed[..], packet[..], "{format:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_other_lengths() {
+        let packet = [0xAB; 64];
+
+        for format in [TextFormat::Hex, TextFormat::Base64] {
+            let line = format.encode(&packet);
+            assert_eq!(parse_text_line(&line, 128), None);
+            assert_eq!(parse_text_line(&line[..line.len() - 4], 64), None);
+        }
+
+        assert_eq!(parse_text_line("", 64), None);
+    }
+}
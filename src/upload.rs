@@ -0,0 +1,456 @@
+// Uploading received packets to an SSDV server, such as the one at
+// ssdv.habhub.org used by the amateur balloon community. Each packet becomes a
+// JSON record with the packet in base64, the payload and receiver callsigns and
+// when it was received, and records are sent by whichever Uploader is in use.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    decoder::{self, Header},
+    encoder::{MIN_PACKET_SIZE, PACKET_SIZE},
+    DecodeError, TextFormat,
+};
+
+/// A received packet, ready to upload
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UploadRecord {
+    /// Callsign of the payload that sent the packet
+    pub callsign: String,
+    /// Callsign of the receiving station
+    pub receiver: String,
+    /// When the packet was received
+    pub received: SystemTime,
+    pub packet: Vec<u8>,
+}
+
+impl UploadRecord {
+    /// Creates a record for a `packet_length` byte packet, after checking its CRC and
+    /// repairing it if needed
+    pub fn new(
+        packet: &[u8],
+        packet_length: usize,
+        receiver: &str,
+        received: SystemTime,
+    ) -> Result<Self, DecodeError> {
+        if !(MIN_PACKET_SIZE..=PACKET_SIZE).contains(&packet_length) {
+            return Err(DecodeError::PacketLength);
+        }
+
+        if packet.len() < packet_length {
+            return Err(DecodeError::Length);
+        }
+
+        let mut data = [0; PACKET_SIZE];
+        data[..packet_length].copy_from_slice(&packet[..packet_length]);
+
+        let header = match Header::parse(&data, packet_length) {
+            Ok(header) => header,
+            Err(err) => decoder::repair(&mut data, packet_length).ok_or(err)?,
+        };
+
+        return Ok(Self {
+            callsign: header.callsign(),
+            receiver: receiver.to_string(),
+            received,
+            packet: data[..packet_length].to_vec(),
+        });
+    }
+
+    /// The record as a JSON object
+    pub fn to_json(&self) -> String {
+        let mut json = String::with_capacity(PACKET_SIZE * 2);
+        json.push_str("{\"type\":\"packet\",\"packet\":\"");
+        json.push_str(&TextFormat::Base64.encode(&self.packet));
+        json.push_str("\",\"encoding\":\"base64\",\"callsign\":");
+        push_json_string(&mut json, &self.callsign);
+        json.push_str(",\"receiver\":");
+        push_json_string(&mut json, &self.receiver);
+        json.push_str(",\"received\":\"");
+        json.push_str(&format_timestamp(self.received));
+        json.push_str("\"}");
+
+        return json;
+    }
+}
+
+/// Several records as one JSON object, for uploading them in a single request
+pub fn upload_json(records: &[UploadRecord]) -> String {
+    let packets: Vec<String> = records.iter().map(UploadRecord::to_json).collect();
+    return format!(
+        "{{\"type\":\"packets\",\"packets\":[{}]}}",
+        packets.join(",")
+    );
+}
+
+/// Somewhere to send received packets
+pub trait Uploader {
+    /// Uploads a batch of records, all or nothing
+    fn upload(&mut self, records: &[UploadRecord]) -> io::Result<()>;
+}
+
+/// Uploads records by POSTing them as JSON to a plain HTTP endpoint
+#[derive(Debug, Clone)]
+pub struct HttpUploader {
+    host: String,
+    port: u16,
+    path: String,
+    timeout: Duration,
+}
+
+impl HttpUploader {
+    /// The habhub SSDV server's upload endpoint
+    pub const HABHUB_URL: &'static str = "http://ssdv.habhub.org/api/v0/packets";
+
+    /// Creates an uploader for an `http://host[:port]/path` URL. HTTPS isn't supported,
+    /// and `https://` URLs are an `Unsupported` error.
+    pub fn new(url: &str) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid upload URL");
+
+        if url.starts_with("https://") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "HTTPS is not supported, use an http:// URL or a local proxy",
+            ));
+        }
+
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = rest.find('/').map_or((rest, "/"), |i| rest.split_at(i));
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| invalid())?)
+            }
+            _ => (authority, 80),
+        };
+
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        return Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            timeout: Duration::from_secs(10),
+        });
+    }
+
+    /// Sets how long to wait for the server when connecting, sending and receiving
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        return Self { timeout, ..self };
+    }
+
+    fn post(&self, body: &str) -> io::Result<()> {
+        let addr = (
+            self.host.trim_start_matches('[').trim_end_matches(']'),
+            self.port,
+        )
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for host"))?;
+
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let host = if self.port == 80 {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        };
+
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\n\
+             Host: {host}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\
+             \r\n\
+             {body}",
+            self.path,
+            body.len(),
+        )?;
+        stream.flush()?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+
+        // Only the status line matters, "HTTP/1.1 200 OK"
+        let status = response
+            .split(|b| *b == b'\n')
+            .next()
+            .and_then(|line| std::str::from_utf8(line).ok())
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response"))?;
+
+        if !(200..300).contains(&status) {
+            return Err(io::Error::other(format!("server responded with {status}")));
+        }
+
+        return Ok(());
+    }
+}
+
+impl Uploader for HttpUploader {
+    fn upload(&mut self, records: &[UploadRecord]) -> io::Result<()> {
+        match records {
+            [] => return Ok(()),
+            [record] => return self.post(&record.to_json()),
+            records => return self.post(&upload_json(records)),
+        }
+    }
+}
+
+fn push_json_string(json: &mut String, s: &str) {
+    json.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+}
+
+/// Formats a time as an ISO 8601 UTC timestamp, "2024-06-01T12:34:56Z"
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Days since the epoch to a civil date, counting years from March so leap days come last
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = era * 400 + year_of_era + (month <= 2) as u64;
+
+    return format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    use super::*;
+    use crate::{Encoder, PacketType, Quality};
+
+    fn packets() -> Vec<Vec<u8>> {
+        Encoder::new(
+            *b"SOMETH",
+            0,
+            Quality::Q3,
+            include_bytes!("../balloon.jpg").to_vec(),
+        )
+        .with_packet_type(PacketType::Normal)
+        .take(3)
+        .map(|packet| packet.unwrap().to_vec())
+        .collect()
+    }
+
+    fn record(packet: &[u8]) -> UploadRecord {
+        let received = UNIX_EPOCH + Duration::from_secs(1_717_245_296);
+        UploadRecord::new(packet, PACKET_SIZE, "M0ABC", received).unwrap()
+    }
+
+    /// Accepts one request, answers it with `status` and returns the head and body
+    fn serve(listener: TcpListener, status: &'static str) -> JoinHandle<(String, String)> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            let head_end = loop {
+                if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break i + 4;
+                }
+                let n = stream.read(&mut buf).unwrap();
+                assert_ne!(n, 0, "connection closed before the headers ended");
+                request.extend_from_slice(&buf[..n]);
+            };
+
+            let head = String::from_utf8(request[..head_end].to_vec()).unwrap();
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+
+            while request.len() < head_end + length {
+                let n = stream.read(&mut buf).unwrap();
+                assert_ne!(n, 0, "connection closed before the body ended");
+                request.extend_from_slice(&buf[..n]);
+            }
+
+            write!(stream, "HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").unwrap();
+
+            let body = String::from_utf8(request[head_end..].to_vec()).unwrap();
+            (head, body)
+        })
+    }
+
+    #[test]
+    fn posts_a_record_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = serve(listener, "200 OK");
+
+        let packet = &packets()[0];
+        let mut uploader = HttpUploader::new(&format!("http://127.0.0.1:{port}/api/v0/packets"))
+            .unwrap()
+            .with_timeout(Duration::from_secs(5));
+        uploader.upload(&[record(packet)]).unwrap();
+
+        let (head, body) = server.join().unwrap();
+        let mut lines = head.lines();
+        assert_eq!(lines.next(), Some("POST /api/v0/packets HTTP/1.1"));
+
+        let headers: Vec<&str> = lines.take_while(|line| !line.is_empty()).collect();
+        let content_length = format!("Content-Length: {}", body.len());
+        assert_eq!(
+            headers,
+            [
+                format!("Host: 127.0.0.1:{port}").as_str(),
+                "Content-Type: application/json",
+                content_length.as_str(),
+                "Connection: close",
+            ]
+        );
+
+        assert_eq!(
+            body,
+            format!(
+                "{{\"type\":\"packet\",\"packet\":\"{}\",\"encoding\":\"base64\",\
+                 \"callsign\":\"SOMETH\",\"receiver\":\"M0ABC\",\
+                 \"received\":\"2024-06-01T12:34:56Z\"}}",
+                TextFormat::Base64.encode(packet)
+            )
+        );
+    }
+
+    #[test]
+    fn posts_batches_as_one_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = serve(listener, "200 OK");
+
+        let records: Vec<UploadRecord> = packets().iter().map(|p| record(p)).collect();
+        HttpUploader::new(&format!("http://127.0.0.1:{port}/"))
+            .unwrap()
+            .upload(&records)
+            .unwrap();
+
+        let (head, body) = server.join().unwrap();
+        assert!(head.starts_with("POST / HTTP/1.1\r\n"));
+        assert_eq!(body, upload_json(&records));
+        assert!(body.starts_with("{\"type\":\"packets\",\"packets\":[{\"type\":\"packet\","));
+        assert_eq!(body.matches("\"type\":\"packet\",").count(), 3);
+    }
+
+    #[test]
+    fn fails_on_error_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = serve(listener, "500 Internal Server Error");
+
+        let err = HttpUploader::new(&format!("http://127.0.0.1:{port}/"))
+            .unwrap()
+            .upload(&[record(&packets()[0])])
+            .unwrap_err();
+        server.join().unwrap();
+
+        assert_eq!(err.to_string(), "server responded with 500");
+    }
+
+    #[test]
+    fn rejects_https() {
+        let err = HttpUploader::new("https://ssdv.habhub.org/api/v0/packets").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        let err = HttpUploader::new("ftp://example.com/").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn parses_urls() {
+        let uploader = HttpUploader::new("http://example.com:8080/api/v0/packets").unwrap();
+        assert_eq!(uploader.host, "example.com");
+        assert_eq!(uploader.port, 8080);
+        assert_eq!(uploader.path, "/api/v0/packets");
+
+        let uploader = HttpUploader::new("http://example.com").unwrap();
+        assert_eq!(uploader.port, 80);
+        assert_eq!(uploader.path, "/");
+
+        assert!(HttpUploader::new("http://:80/").is_err());
+        assert!(HttpUploader::new("http://example.com:port/").is_err());
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        let mut json = String::new();
+        push_json_string(&mut json, "a\"b\\c\n\u{1}");
+        assert_eq!(json, "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "2000-02-29T00:00:00Z"
+        );
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(1_717_245_296)),
+            "2024-06-01T12:34:56Z"
+        );
+    }
+
+    #[test]
+    fn repairs_damaged_packets() {
+        let packet = &packets()[0];
+        let mut damaged = packet.clone();
+        damaged[20] ^= 0xff;
+
+        assert_eq!(record(&damaged).packet, *packet);
+        assert_eq!(
+            UploadRecord::new(&packet[..100], PACKET_SIZE, "M0ABC", UNIX_EPOCH),
+            Err(DecodeError::Length)
+        );
+    }
+}
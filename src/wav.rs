@@ -0,0 +1,280 @@
+// Just enough of WAV to write mono 16-bit PCM audio from the modems, and to read
+// back recordings in the common PCM and floating point formats.
+
+use std::io::{self, Read, Write};
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+const BITS_PER_SAMPLE: u16 = 16;
+
+/// Size of the largest fmt chunk, WAVE_FORMAT_EXTENSIBLE's
+const MAX_FMT_SIZE: usize = 40;
+
+/// Writes mono 16-bit PCM samples as a WAV file
+pub fn write_wav<W: Write>(writer: &mut W, sample_rate: u32, samples: &[i16]) -> io::Result<()> {
+    let data_size = u32::try_from(samples.len() * 2)
+        .ok()
+        .filter(|size| *size <= u32::MAX - 36)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many samples for WAV"))?;
+
+    let block_align = BITS_PER_SAMPLE / 8;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&FORMAT_PCM.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?; // Channels
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+
+    let mut data = Vec::with_capacity(samples.len() * 2);
+    for sample in samples {
+        data.extend_from_slice(&sample.to_le_bytes());
+    }
+    writer.write_all(&data)?;
+
+    return Ok(());
+}
+
+/// Reads a PCM or floating point WAV file, returning its sample rate and samples. Files
+/// with more than one channel are mixed down to mono.
+pub fn read_wav<R: Read>(reader: &mut R) -> io::Result<(u32, Vec<i16>)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let mut header = [0; 12];
+    reader.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err(invalid("not a WAV file"));
+    }
+
+    let mut format = None;
+    loop {
+        let mut chunk = [0; 8];
+        reader.read_exact(&mut chunk)?;
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as usize;
+
+        match &chunk[0..4] {
+            b"fmt " => {
+                if !(16..=MAX_FMT_SIZE).contains(&size) {
+                    return Err(invalid("invalid fmt chunk"));
+                }
+
+                // Chunks are padded to an even size
+                let mut fmt = vec![0; size + size % 2];
+                reader.read_exact(&mut fmt)?;
+
+                let mut tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+                // WAVE_FORMAT_EXTENSIBLE keeps the real format at the start of its subformat GUID
+                if tag == FORMAT_EXTENSIBLE && size >= 26 {
+                    tag = u16::from_le_bytes([fmt[24], fmt[25]]);
+                }
+
+                format = Some(Format {
+                    tag,
+                    channels: u16::from_le_bytes([fmt[2], fmt[3]]) as usize,
+                    sample_rate: u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]),
+                    bits: u16::from_le_bytes([fmt[14], fmt[15]]),
+                });
+            }
+            b"data" => {
+                let format = format.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+
+                // Files still being recorded can have a size of 0 or of everything possible,
+                // so read whatever is there
+                let mut data = Vec::new();
+                reader.take(size as u64).read_to_end(&mut data)?;
+                if size == 0 {
+                    reader.read_to_end(&mut data)?;
+                }
+
+                return Ok((format.sample_rate, format.samples(&data)?));
+            }
+            _ => {
+                let padded = size
+                    .checked_add(size % 2)
+                    .ok_or_else(|| invalid("chunk too large"))?;
+                io::copy(&mut reader.take(padded as u64), &mut io::sink())?;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Format {
+    tag: u16,
+    channels: usize,
+    sample_rate: u32,
+    bits: u16,
+}
+
+impl Format {
+    /// Converts the data chunk to 16-bit mono samples
+    fn samples(&self, data: &[u8]) -> io::Result<Vec<i16>> {
+        let sample: fn(&[u8]) -> f64 = match (self.tag, self.bits) {
+            (FORMAT_PCM, 8) => |b| (b[0] as f64 - 128.0) * 256.0,
+            (FORMAT_PCM, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f64,
+            (FORMAT_PCM, 24) => |b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f64 / 65536.0,
+            (FORMAT_PCM, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64 / 65536.0,
+            (FORMAT_FLOAT, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64 * 32767.0,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "unsupported WAV sample format",
+                ));
+            }
+        };
+
+        if self.channels == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "WAV file has no channels",
+            ));
+        }
+
+        let width = self.bits as usize / 8;
+        let samples = data
+            .chunks_exact(width * self.channels)
+            .map(|frame| {
+                let sum: f64 = frame.chunks_exact(width).map(sample).sum();
+                return (sum / self.channels as f64).clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+            })
+            .collect();
+
+        return Ok(samples);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_pcm_header() {
+        let mut wav = Vec::new();
+        write_wav(&mut wav, 48000, &[0, 1, -1, i16::MAX]).unwrap();
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(b"RIFF");
+        expected.extend_from_slice(&44u32.to_le_bytes());
+        expected.extend_from_slice(b"WAVEfmt ");
+        expected.extend_from_slice(&16u32.to_le_bytes());
+        expected.extend_from_slice(&[1, 0, 1, 0]); // PCM, mono
+        expected.extend_from_slice(&48000u32.to_le_bytes());
+        expected.extend_from_slice(&96000u32.to_le_bytes());
+        expected.extend_from_slice(&[2, 0, 16, 0]); // Block align, bits per sample
+        expected.extend_from_slice(b"data");
+        expected.extend_from_slice(&8u32.to_le_bytes());
+        expected.extend_from_slice(&[0, 0, 1, 0, 0xff, 0xff, 0xff, 0x7f]);
+
+        assert_eq!(wav, expected);
+    }
+
+    #[test]
+    fn reads_back_written_samples() {
+        let samples: Vec<i16> = (0..1000).map(|i| (i * 37 % 65536 - 32768) as i16).collect();
+
+        let mut wav = Vec::new();
+        write_wav(&mut wav, 22050, &samples).unwrap();
+
+        assert_eq!(read_wav(&mut wav.as_slice()).unwrap(), (22050, samples));
+    }
+
+    /// A WAV file with the given chunks after the RIFF header
+    fn wav(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut body = b"WAVE".to_vec();
+        for (id, data) in chunks {
+            body.extend_from_slice(*id);
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&body);
+        return wav;
+    }
+
+    fn fmt(tag: u16, channels: u16, bits: u16) -> Vec<u8> {
+        let mut fmt = Vec::new();
+        fmt.extend_from_slice(&tag.to_le_bytes());
+        fmt.extend_from_slice(&channels.to_le_bytes());
+        fmt.extend_from_slice(&8000u32.to_le_bytes());
+        fmt.extend_from_slice(&(8000 * (bits / 8 * channels) as u32).to_le_bytes());
+        fmt.extend_from_slice(&(bits / 8 * channels).to_le_bytes());
+        fmt.extend_from_slice(&bits.to_le_bytes());
+        return fmt;
+    }
+
+    #[test]
+    fn mixes_down_and_converts_samples() {
+        let stereo: Vec<u8> = [1000i16, 3000, -2000, -4000]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        let file = wav(&[
+            (b"LIST", b"odd"),
+            (b"fmt ", &fmt(FORMAT_PCM, 2, 16)),
+            (b"data", &stereo),
+        ]);
+        assert_eq!(
+            read_wav(&mut file.as_slice()).unwrap(),
+            (8000, vec![2000, -3000])
+        );
+
+        let float: Vec<u8> = [0.5f32, -1.0]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        let file = wav(&[(b"fmt ", &fmt(FORMAT_FLOAT, 1, 32)), (b"data", &float)]);
+        assert_eq!(
+            read_wav(&mut file.as_slice()).unwrap(),
+            (8000, vec![16383, -32767])
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let read = |file: Vec<u8>| read_wav(&mut file.as_slice()).unwrap_err().kind();
+
+        assert_eq!(
+            read(b"RIFX\0\0\0\0WAVE".to_vec()),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(read(wav(&[(b"data", &[0, 0])])), io::ErrorKind::InvalidData);
+        assert_eq!(
+            read(wav(&[
+                (b"fmt ", &fmt(FORMAT_PCM, 1, 12)),
+                (b"data", &[0, 0])
+            ])),
+            io::ErrorKind::Unsupported
+        );
+        assert_eq!(
+            read(wav(&[(b"fmt ", &fmt(FORMAT_PCM, 1, 16)[..14])])),
+            io::ErrorKind::InvalidData
+        );
+
+        // A fmt chunk claiming to be huge is rejected rather than allocated
+        let mut file = wav(&[]);
+        file.extend_from_slice(b"fmt ");
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(read(file), io::ErrorKind::InvalidData);
+
+        // As is running out of file partway through skipping a chunk
+        let mut file = wav(&[]);
+        file.extend_from_slice(b"junk");
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(read(file), io::ErrorKind::UnexpectedEof);
+    }
+}